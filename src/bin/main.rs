@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, Result};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
@@ -9,6 +11,8 @@ use psynth::{
     Pot,
     control,
     sampling,
+    patch,
+    observer,
     Consumer,
     FilterComposable,
     Sample,
@@ -17,25 +21,58 @@ use psynth::{
 };
 
 
+/// Interval at which a patch file is checked for changes.
+const PATCH_POLL: Duration = Duration::from_millis(500);
+
+
+/// Play the patch file at the path provided as the first argument, if any, reloading it whenever
+/// it changes. Otherwise play the patch hardcoded below.
 fn main() -> Result<()> {
+    observer::install_interrupt_handler()?;
+
     let host = cpal::default_host();
     let output_device = host
         .default_output_device()
         .ok_or_else(|| anyhow!("missing default output device"))?;
 
+    let patch_path = std::env::args().nth(1);
+    let initial = match &patch_path {
+        Some(path) => Some(patch::Patch::load(path)?),
+        None => None,
+    };
+
     // hardcode to match WAV files, not the best solution but OK for now
-    let config = cpal::StreamConfig { channels: 2, sample_rate: cpal::SampleRate(44100) };
+    let patch_channels = initial.as_ref().and_then(|patch| patch.channels()).unwrap_or(2);
+    let config = cpal::StreamConfig {
+        channels: patch_channels as u16,
+        sample_rate: cpal::SampleRate(44100),
+    };
     println!("config: {:?}", config);
 
     let channels = config.channels as usize;
     let rate: u32 = config.sample_rate.0;
 
-    let (l, r) = control::mux::balance(
-        control::pot::sine_pot(rate, 4.0, -1.0, 1.0),
-        generator::sine(rate, 440.0).compose(filter::gain(0.1)),
-        generator::sine(rate, 330.0).compose(filter::gain(0.1)),
-    );
-    let mut consumer = consumer::StereoConsumer::new(channels).bind(l, r);
+    let mut swapper = None;
+    let mut consumer: Box<dyn Consumer> = match &initial {
+        Some(patch) => {
+            let (patch_swapper, swappable) = patch::swappable(
+                rate,
+                patch::PATCH_FADE_SECS,
+                channels,
+                patch.build(rate, channels)?,
+            );
+            swapper = Some(patch_swapper);
+            Box::new(swappable)
+        },
+        None => {
+            let (l, r) = control::mux::balance(
+                control::pot::sine_pot(rate, 4.0, -1.0, 1.0),
+                generator::sine(rate, 440.0).compose(filter::gain(0.1)),
+                generator::sine(rate, 330.0).compose(filter::gain(0.1)),
+            );
+            Box::new(consumer::StereoConsumer::new(channels).bind(l, r))
+        },
+    };
 
     let output_stream = output_device.build_output_stream(
         &config,
@@ -44,8 +81,40 @@ fn main() -> Result<()> {
     )?;
     output_stream.play()?;
 
-    // time out after 60 seconds
-    std::thread::sleep(std::time::Duration::from_secs(600));
+    if let (Some(path), Some(swapper)) = (patch_path, swapper) {
+        return watch(&path, rate, channels, swapper);
+    }
+
+    // time out after 600 seconds
+    let start = std::time::Instant::now();
+    while !observer::interrupted() && start.elapsed() < Duration::from_secs(600) {
+        std::thread::sleep(PATCH_POLL);
+    }
+
+    Ok(())
+}
+
 
+// reload the patch at `path` whenever it is modified, until interrupted
+fn watch(path: &str, rate: u32, channels: usize, mut swapper: patch::Swapper) -> Result<()> {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(path);
+    while !observer::interrupted() {
+        std::thread::sleep(PATCH_POLL);
+        swapper.collect_garbage();
+        let current = modified(path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        // a broken edit keeps the previous patch playing
+        match patch::Patch::load(path).and_then(|patch| patch.build(rate, channels)) {
+            Ok(consumer) => match swapper.swap(consumer) {
+                Ok(()) => println!("reloaded '{}'", path),
+                Err(e) => eprintln!("unable to reload '{}': {:#}", path, e),
+            },
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result};
+
+#[allow(unused_imports)]
+use psynth::{
+    generator,
+    filter,
+    consumer,
+    render,
+    patch,
+    Pot,
+    control,
+    sampling,
+    Consumer,
+    FilterComposable,
+    Sample,
+    music::notes,
+};
+
+
+const USAGE: &str = "usage: psynth-render <output.wav> [seconds] [patch.toml]";
+
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let secs: f64 = match args.next() {
+        Some(secs) => secs.parse().map_err(|e| anyhow!("invalid duration '{}': {}", secs, e))?,
+        None => 10.0,
+    };
+
+    let patch = match args.next() {
+        Some(patch_path) => Some(patch::Patch::load(patch_path)?),
+        None => None,
+    };
+
+    let channels = patch.as_ref().and_then(|patch| patch.channels()).unwrap_or(2);
+    let rate: u32 = 44100;
+
+    // without a patch file, the same patch as `psynth-play` bounced instead of played
+    let mut consumer: Box<dyn Consumer> = match &patch {
+        Some(patch) => patch.build(rate, channels)?,
+        None => {
+            let (l, r) = control::mux::balance(
+                control::pot::sine_pot(rate, 4.0, -1.0, 1.0),
+                generator::sine(rate, 440.0).compose(filter::gain(0.1)),
+                generator::sine(rate, 330.0).compose(filter::gain(0.1)),
+            );
+            Box::new(consumer::StereoConsumer::new(channels).bind(l, r))
+        },
+    };
+
+    let start = std::time::Instant::now();
+    render::render_to_wav(&path, consumer.as_mut(), rate, channels as u16, secs)?;
+    println!("rendered {}s to '{}' in {:?}", secs, path, start.elapsed());
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Block-based processing alongside the per-sample `Generator` and `Filter` closures.
+//!
+//! A boxed closure costs a dynamic call per sample, and chains of them can't be inlined or
+//! vectorized. The traits here work on whole buffers at a time instead. Chains built from
+//! concrete types with `BlockGenerator::compose_block` are statically dispatched end to end,
+//! leaving only one dynamic call per block at the `Consumer`.
+//!
+//! Every `Generator`, `Filter` and `FrameGenerator` is also a block processor through the
+//! implementations below, and `into_generator`/`into_filter` go the other way, so the two styles
+//! can be mixed freely.
+
+use crate::{Sample, Frame, Generator, Filter, FrameGenerator, Pot};
+
+
+/// Source of an audio stream produced a block at a time, the block equivalent of a `Generator`.
+pub trait BlockGenerator: Send {
+    /// Overwrite every value of the provided buffer with the next samples of the stream.
+    fn fill_block(&mut self, block: &mut [Sample]);
+
+    /// Apply the provided `BlockFilter` to this stream.
+    fn compose_block<F>(self, filter: F) -> Compose<Self, F>
+    where
+        Self: Sized,
+        F: BlockFilter,
+    {
+        Compose { generator: self, filter }
+    }
+}
+
+
+/// Transformation applied to an audio stream a block at a time, the block equivalent of a
+/// `Filter`.
+pub trait BlockFilter: Send {
+    /// Transform the provided buffer in place.
+    fn process_block(&mut self, block: &mut [Sample]);
+}
+
+
+/// Source of an `N`-channel audio stream produced a block of frames at a time.
+pub trait BlockFrameGenerator<const N: usize>: Send {
+    fn fill_frames(&mut self, block: &mut [Frame<N>]);
+}
+
+
+impl BlockGenerator for Generator {
+    fn fill_block(&mut self, block: &mut [Sample]) {
+        for value in block.iter_mut() {
+            *value = self();
+        }
+    }
+}
+
+
+impl BlockFilter for Filter {
+    fn process_block(&mut self, block: &mut [Sample]) {
+        for value in block.iter_mut() {
+            *value = self(*value);
+        }
+    }
+}
+
+
+impl<const N: usize> BlockFrameGenerator<N> for FrameGenerator<N> {
+    fn fill_frames(&mut self, block: &mut [Frame<N>]) {
+        for frame in block.iter_mut() {
+            *frame = self();
+        }
+    }
+}
+
+
+/// A `BlockGenerator` followed by a `BlockFilter`, as built by `BlockGenerator::compose_block`.
+pub struct Compose<G, F> {
+    generator: G,
+    filter: F,
+}
+
+
+impl<G, F> BlockGenerator for Compose<G, F>
+where
+    G: BlockGenerator,
+    F: BlockFilter,
+{
+    fn fill_block(&mut self, block: &mut [Sample]) {
+        self.generator.fill_block(block);
+        self.filter.process_block(block);
+    }
+}
+
+
+/// Statically dispatched `BlockGenerator` calling an unboxed closure once per sample.
+pub struct FromFn<F>(F);
+
+
+/// Build a `BlockGenerator` from a closure without boxing it, so that it can be inlined into the
+/// block loop.
+pub fn from_fn<F>(function: F) -> FromFn<F>
+where
+    F: FnMut() -> Sample + Send,
+{
+    FromFn(function)
+}
+
+
+impl<F> BlockGenerator for FromFn<F>
+where
+    F: FnMut() -> Sample + Send,
+{
+    fn fill_block(&mut self, block: &mut [Sample]) {
+        for value in block.iter_mut() {
+            *value = (self.0)();
+        }
+    }
+}
+
+
+/// Statically dispatched `BlockFilter` calling an unboxed closure once per sample.
+pub struct MapFn<F>(F);
+
+
+/// Build a `BlockFilter` from a closure without boxing it, so that it can be inlined into the
+/// block loop.
+pub fn map_fn<F>(function: F) -> MapFn<F>
+where
+    F: FnMut(Sample) -> Sample + Send,
+{
+    MapFn(function)
+}
+
+
+impl<F> BlockFilter for MapFn<F>
+where
+    F: FnMut(Sample) -> Sample + Send,
+{
+    fn process_block(&mut self, block: &mut [Sample]) {
+        for value in block.iter_mut() {
+            *value = (self.0)(*value);
+        }
+    }
+}
+
+
+/// Scale the stream by the provided factor, read once per block.
+///
+/// Reading at block rate suits slowly changing settings. For a factor that needs per-sample
+/// smoothing, use `filter::gain` with the `Pot` wrapped in one of the `control::smooth`
+/// smoothers, which advance once per read.
+pub struct Gain<P>(pub P);
+
+
+impl<P> BlockFilter for Gain<P>
+where
+    P: Pot<f32>,
+{
+    fn process_block(&mut self, block: &mut [Sample]) {
+        let factor = self.0.read();
+        for value in block.iter_mut() {
+            *value *= factor;
+        }
+    }
+}
+
+
+/// Wrap a `BlockGenerator` as a per-sample `Generator`, generating `block_size` samples at a time.
+pub fn into_generator<G>(mut generator: G, block_size: usize) -> Generator
+where
+    G: BlockGenerator + 'static,
+{
+    let mut buffer = vec![0.0; block_size.max(1)];
+    let mut position = buffer.len();
+    Box::new(move || {
+        if position == buffer.len() {
+            generator.fill_block(&mut buffer);
+            position = 0;
+        }
+        position += 1;
+        buffer[position - 1]
+    })
+}
+
+
+/// Wrap a `BlockFilter` as a per-sample `Filter`.
+///
+/// Each sample is processed as a block of one so that no latency is added, which gives up the
+/// benefits of block processing for this stage.
+pub fn into_filter<F>(mut filter: F) -> Filter
+where
+    F: BlockFilter + 'static,
+{
+    Box::new(move |sample: Sample| {
+        let mut block = [sample];
+        filter.process_block(&mut block);
+        block[0]
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter;
+
+    #[test]
+    fn test_block_matches_per_sample() {
+        let mut counter = 0f32;
+        let mut blocks = from_fn(move || {
+            counter += 1.0;
+            counter
+        })
+        .compose_block(Gain(0.5))
+        .compose_block(filter::offset(1.0));
+        let mut block = [0.0; 4];
+        blocks.fill_block(&mut block);
+        assert_eq!(block, [1.5, 2.0, 2.5, 3.0]);
+
+        let mut generator = into_generator(blocks, 3);
+        let values: Vec<Sample> = (0 .. 4).map(|_| generator()).collect();
+        assert_eq!(values, vec![3.5, 4.0, 4.5, 5.0]);
+    }
+}
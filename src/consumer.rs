@@ -1,17 +1,26 @@
+use anyhow::{anyhow, Result};
+
 use crate::{
+    frame,
     Sample,
+    Frame,
     Consumer,
     Generator,
+    StereoGenerator,
     Observer,
 };
+use crate::block::{BlockGenerator, BlockFrameGenerator};
+use crate::context::AudioContext;
+use crate::observer::ObserverTap;
 
 
 /// Duplicates single-stream audio to as many channels as necessary.
 pub struct MonoConsumer {
     channels: usize,
-    generator: Option<Generator>,
-    observer_channel: Option<std::sync::mpsc::Sender<Sample>>,
-    observer_thread_handle: Option<std::thread::JoinHandle<Sample>>,
+    generator: Option<Box<dyn BlockGenerator>>,
+    block: Vec<Sample>,
+    context: Option<AudioContext>,
+    observers: Option<ObserverTap>,
 }
 
 
@@ -20,26 +29,33 @@ impl MonoConsumer {
         Self {
             channels: channels,
             generator: None,
-            observer_channel: None,
-            observer_thread_handle: None,
+            block: Vec::new(),
+            context: None,
+            observers: None,
         }
     }
 
-    pub fn bind(mut self, generator: Generator) -> Self {
-        self.generator = Some(generator);
+    pub fn bind(self, generator: Generator) -> Self {
+        self.bind_block(generator)
+    }
+
+    pub fn bind_block<G>(mut self, generator: G) -> Self
+    where
+        G: BlockGenerator + 'static,
+    {
+        self.generator = Some(Box::new(generator));
         self
     }
 
-    // TODO: make part of the `Consumer` trait? add new `Observable` trait? keep here?
-    pub fn bind_observers(mut self, mut observers: Vec<Box<dyn Observer + Send>>) -> Self {
-        let (sender, receiver) = std::sync::mpsc::channel();
-        self.observer_thread_handle = Some(std::thread::spawn(move || loop {
-            let sample = receiver.recv().expect("channel closed");
-            for observer in observers.iter_mut() {
-                observer.sample(sample);
-            }
-        }));
-        self.observer_channel = Some(sender);
+    /// Advance the clock of the provided context as buffers are filled.
+    pub fn with_context(mut self, context: AudioContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Feed the stream to the provided observers, on a thread of their own. See `ObserverTap`.
+    pub fn bind_observers(mut self, observers: Vec<Box<dyn Observer + Send>>) -> Self {
+        self.observers = Some(ObserverTap::spawn(1, observers));
         self
     }
 }
@@ -53,15 +69,26 @@ impl Consumer for MonoConsumer {
         // TODO: not crazy about the verbosity of this technique used to access self.generator
         match &mut self.generator {
             Some(ref mut gen) => {
-                for frame in output_buffer.chunks_mut(self.channels) {
+                let n_frames = output_buffer.len() / self.channels;
+                // only allocates when the device hands over a larger buffer than ever before
+                self.block.resize(n_frames, 0.0);
+                gen.fill_block(&mut self.block);
+                let mut frames = output_buffer.chunks_exact_mut(self.channels);
+                for (frame, &sample) in (&mut frames).zip(self.block.iter()) {
                     // TODO: reintroduce value type parameterization from example
-                    let sample = gen();
                     for location in frame.iter_mut() {
                         *location = sample;
                     }
-                    if let Some(ref sender) = &self.observer_channel {
-                        sender.send(sample).expect("channel closed");
-                    }
+                }
+                // a trailing partial frame has no generated sample to write
+                for location in frames.into_remainder().iter_mut() {
+                    *location = 0.0;
+                }
+                if let Some(observers) = &mut self.observers {
+                    observers.push(&self.block);
+                }
+                if let Some(context) = &self.context {
+                    context.advance(n_frames as u64);
                 }
             },
             None => panic!("`fill` called on unbound `Consumer`"),
@@ -74,10 +101,14 @@ impl Consumer for MonoConsumer {
 ///
 /// NOTE: if the number of channels is not exactly 2, the behavior defaults to that of the
 /// `MonoConsumer` where each channel has the same data written to it. Here that data is the sum
-/// of the two held streams.
+/// of the two held streams. Use a `MultiChannelConsumer` to place stereo on devices with more
+/// channels.
 pub struct StereoConsumer {
     channels: usize,
-    generators: Option<(Generator, Generator)>,
+    generator: Option<Box<dyn BlockFrameGenerator<2>>>,
+    block: Vec<Frame<2>>,
+    context: Option<AudioContext>,
+    observers: Option<ObserverTap>,
 }
 
 
@@ -89,12 +120,38 @@ impl StereoConsumer {
         }
         Self {
             channels: channels,
-            generators: None,
+            generator: None,
+            block: Vec::new(),
+            context: None,
+            observers: None,
         }
     }
 
-    pub fn bind(mut self, left: Generator, right: Generator) -> Self {
-        self.generators = Some((left, right));
+    pub fn bind(self, left: Generator, right: Generator) -> Self {
+        self.bind_frames(frame::stereo(left, right))
+    }
+
+    pub fn bind_frames(self, generator: StereoGenerator) -> Self {
+        self.bind_block(generator)
+    }
+
+    pub fn bind_block<G>(mut self, generator: G) -> Self
+    where
+        G: BlockFrameGenerator<2> + 'static,
+    {
+        self.generator = Some(Box::new(generator));
+        self
+    }
+
+    /// Advance the clock of the provided context as buffers are filled.
+    pub fn with_context(mut self, context: AudioContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Feed the stream to the provided observers, on a thread of their own. See `ObserverTap`.
+    pub fn bind_observers(mut self, observers: Vec<Box<dyn Observer + Send>>) -> Self {
+        self.observers = Some(ObserverTap::spawn(2, observers));
         self
     }
 }
@@ -102,20 +159,240 @@ impl StereoConsumer {
 
 impl Consumer for StereoConsumer {
     fn fill(&mut self, output_buffer: &mut [Sample]) {
-        if let Some((ref mut gen_l, ref mut gen_r)) = &mut self.generators {
-            for frame in output_buffer.chunks_mut(self.channels) {
+        if let Some(ref mut gen) = &mut self.generator {
+            self.block.resize(output_buffer.len() / self.channels, [0.0; 2]);
+            gen.fill_frames(&mut self.block);
+            let mut frames = output_buffer.chunks_exact_mut(self.channels);
+            for (frame, &[l, r]) in (&mut frames).zip(self.block.iter()) {
                 if self.channels == 2 {
-                    frame[0] = gen_l();
-                    frame[1] = gen_r();
+                    frame[0] = l;
+                    frame[1] = r;
                 } else {
-                    let sample = gen_l() + gen_r();
+                    let sample = l + r;
                     for location in frame.iter_mut() {
                         *location = sample;
                     }
                 }
             }
+            // a trailing partial frame has no generated samples to write
+            for location in frames.into_remainder().iter_mut() {
+                *location = 0.0;
+            }
+            if let Some(observers) = &mut self.observers {
+                observers.push_frames(&self.block);
+            }
+            if let Some(context) = &self.context {
+                context.advance(self.block.len() as u64);
+            }
         } else {
             panic!("`StereoConsumer::fill` called but generators have not been bound");
         }
     }
 }
+
+
+/// Gains routing each input stream of a `MultiChannelConsumer` to each output channel.
+///
+/// Row `i` of the matrix holds the gain of every input stream in output channel `i`, so a
+/// matrix has one row per output channel and one column per input stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Routing {
+    matrix: Vec<Vec<f32>>,
+}
+
+
+impl Routing {
+    /// Route input `i` to output channel `i`.
+    pub fn identity(channels: usize) -> Self {
+        Self::map(&(0 .. channels).collect::<Vec<usize>>(), channels)
+    }
+
+    /// Route input `inputs[i]` to output channel `i` at unity gain, out of `n_inputs` inputs.
+    ///
+    /// E.g. `Routing::map(&[0, 1, 0, 1], 2)` duplicates a stereo pair onto four outputs.
+    pub fn map(inputs: &[usize], n_inputs: usize) -> Self {
+        let matrix = inputs
+            .iter()
+            .map(|&input| (0 .. n_inputs).map(|j| if j == input { 1.0 } else { 0.0 }).collect())
+            .collect();
+        Self { matrix }
+    }
+
+    /// Route with an arbitrary matrix of gains, one row per output channel.
+    pub fn matrix(matrix: Vec<Vec<f32>>) -> Self {
+        Self { matrix }
+    }
+
+    pub fn n_outputs(&self) -> usize {
+        self.matrix.len()
+    }
+
+    // fail unless the matrix is `channels` rows of `n_inputs` gains
+    fn validate(&self, channels: usize, n_inputs: usize) -> Result<()> {
+        if self.matrix.len() != channels {
+            return Err(anyhow!(
+                "routing has {} output channels but the device has {}", self.matrix.len(), channels
+            ));
+        }
+        match self.matrix.iter().position(|row| row.len() != n_inputs) {
+            Some(i) => Err(anyhow!(
+                "routing for output channel {} has {} gains for {} input streams",
+                i, self.matrix[i].len(), n_inputs
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Consumer routing any number of independent streams to an output device of any number of
+/// channels.
+pub struct MultiChannelConsumer {
+    channels: usize,
+    generators: Vec<Box<dyn BlockGenerator>>,
+    routing: Routing,
+    blocks: Vec<Vec<Sample>>,
+    context: Option<AudioContext>,
+    observers: Option<ObserverTap>,
+}
+
+
+impl MultiChannelConsumer {
+    /// Bind one `Generator` to each of the device's `channels`, in order.
+    ///
+    /// Fails if the number of `generators` doesn't match the number of `channels`.
+    pub fn try_new(channels: usize, generators: Vec<Generator>) -> Result<Self> {
+        if generators.len() != channels {
+            return Err(anyhow!(
+                "{} generators provided for a device with {} channels", generators.len(), channels
+            ));
+        }
+        Self::with_routing(channels, generators, Routing::identity(channels))
+    }
+
+    /// Bind any number of `Generator`s, mixed onto the device's `channels` by the provided
+    /// `routing`.
+    ///
+    /// Fails if `channels` is 0 or the `routing` doesn't map `generators.len()` inputs onto
+    /// `channels` outputs.
+    pub fn with_routing(
+        channels: usize,
+        generators: Vec<Generator>,
+        routing: Routing,
+    ) -> Result<Self> {
+        if channels == 0 {
+            return Err(anyhow!("unable to route audio to a device with 0 channels"));
+        }
+        let generators: Vec<Box<dyn BlockGenerator>> = generators
+            .into_iter()
+            .map(|gen| Box::new(gen) as Box<dyn BlockGenerator>)
+            .collect();
+        routing.validate(channels, generators.len())?;
+        Ok(Self {
+            channels,
+            blocks: vec![Vec::new(); generators.len()],
+            generators,
+            routing,
+            context: None,
+            observers: None,
+        })
+    }
+
+    /// Advance the clock of the provided context as buffers are filled.
+    pub fn with_context(mut self, context: AudioContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Feed the stream to the provided observers, on a thread of their own. See `ObserverTap`.
+    pub fn bind_observers(mut self, observers: Vec<Box<dyn Observer + Send>>) -> Self {
+        self.observers = Some(ObserverTap::spawn(self.channels, observers));
+        self
+    }
+
+    /// Replace the routing matrix, keeping the current one if the new one doesn't fit.
+    pub fn set_routing(&mut self, routing: Routing) -> Result<()> {
+        routing.validate(self.channels, self.generators.len())?;
+        self.routing = routing;
+        Ok(())
+    }
+}
+
+
+impl Consumer for MultiChannelConsumer {
+    fn fill(&mut self, output_buffer: &mut [Sample]) {
+        let n_frames = output_buffer.len() / self.channels;
+        for (gen, block) in self.generators.iter_mut().zip(self.blocks.iter_mut()) {
+            block.resize(n_frames, 0.0);
+            gen.fill_block(block);
+        }
+        let mut frames = output_buffer.chunks_exact_mut(self.channels);
+        for (i, frame) in (&mut frames).enumerate() {
+            for (location, gains) in frame.iter_mut().zip(self.routing.matrix.iter()) {
+                *location = gains
+                    .iter()
+                    .zip(self.blocks.iter())
+                    .fold(0.0, |acc, (gain, block)| acc + gain * block[i]);
+            }
+        }
+        // a trailing partial frame has no generated samples to route
+        for location in frames.into_remainder().iter_mut() {
+            *location = 0.0;
+        }
+        if let Some(observers) = &mut self.observers {
+            observers.push(&output_buffer[.. n_frames * self.channels]);
+        }
+        if let Some(context) = &self.context {
+            context.advance(n_frames as u64);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn constant(value: Sample) -> Generator {
+        Box::new(move || value)
+    }
+
+    #[test]
+    fn test_multichannel_routing() {
+        assert!(MultiChannelConsumer::try_new(4, vec![constant(1.0), constant(2.0)]).is_err());
+
+        let mut consumer = MultiChannelConsumer::with_routing(
+            4,
+            vec![constant(1.0), constant(2.0)],
+            Routing::map(&[0, 1, 1, 0], 2),
+        ).unwrap();
+        let mut buffer = [0.0; 8];
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer, [1.0, 2.0, 2.0, 1.0, 1.0, 2.0, 2.0, 1.0]);
+
+        assert!(consumer.set_routing(Routing::identity(2)).is_err());
+        consumer.set_routing(Routing::matrix(vec![vec![0.5, 0.5]; 4])).unwrap();
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer, [1.5; 8]);
+
+        // a buffer holding a partial frame
+        let mut buffer = [1.0; 9];
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer[8], 0.0);
+
+        assert!(MultiChannelConsumer::try_new(0, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let mut mono = MonoConsumer::new(2).bind(constant(0.5));
+        let mut buffer = [1.0; 5];
+        mono.fill(&mut buffer);
+        assert_eq!(buffer, [0.5, 0.5, 0.5, 0.5, 0.0]);
+
+        let mut stereo = StereoConsumer::new(2).bind(constant(0.5), constant(-0.5));
+        let mut buffer = [1.0; 5];
+        stereo.fill(&mut buffer);
+        assert_eq!(buffer, [0.5, -0.5, 0.5, -0.5, 0.0]);
+    }
+}
@@ -0,0 +1,354 @@
+//! Shared audio context: sample rate, sample clock and musical transport.
+//!
+//! Constructors taking a raw `sample_rate` each count samples privately, so two `Generator`s
+//! created at different times disagree on what time it is and a sample rate has to be threaded
+//! through by hand. An `AudioContext` is shared instead (it is cheap to clone) by everything
+//! built from it and by the `Consumer` driving the stream, which advances its clock as it fills
+//! each buffer. `Generator`s and `Filter`s built by the context read the sample rate as they run,
+//! so a call to `set_sample_rate` reaches all of them.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicU32, AtomicU64, Ordering};
+
+use crate::{Sample, Generator, Filter, Pot, FilterComposable};
+use crate::control::pot::GeneratorPot;
+use crate::control::smooth::OnePole;
+use crate::filter;
+use crate::sampling::SampleTrack;
+
+
+/// Resolution of a `Position`, in ticks per beat.
+pub const TICKS_PER_BEAT: u32 = 960;
+
+
+/// Musical position in the transport, all fields counted from 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub bar: u64,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+
+impl Position {
+    fn from_beats(beats: f64, beats_per_bar: u32) -> Self {
+        let whole_beats = beats.floor() as u64;
+        Self {
+            bar: whole_beats / beats_per_bar as u64,
+            beat: (whole_beats % beats_per_bar as u64) as u32,
+            tick: ((beats - beats.floor()) * TICKS_PER_BEAT as f64) as u32,
+        }
+    }
+}
+
+
+/// Displayed 1-based, as in a sequencer: `1.1.000` is the very start.
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{:03}", self.bar + 1, self.beat + 1, self.tick)
+    }
+}
+
+
+// floats are stored as their bit patterns to be shared atomically. `clock` and `beats_bits` are
+// updated together under `sequence`, odd while an update is in progress (a seqlock), so that
+// readers never pair the beats of one buffer with the clock of another
+struct ContextState {
+    sample_rate: AtomicU32,
+    tempo_bits: AtomicU32,
+    beats_per_bar: AtomicU32,
+    sequence: AtomicU64,
+    clock: AtomicU64,
+    beats_bits: AtomicU64,
+}
+
+
+/// Shared sample rate, sample clock, tempo and musical position.
+///
+/// The clock counts frames (one sample per channel) and is advanced by the `Consumer` it is
+/// bound to, so there should be exactly one such `Consumer` per context.
+#[derive(Clone)]
+pub struct AudioContext {
+    state: Arc<ContextState>,
+}
+
+
+impl AudioContext {
+    /// Create a context at the provided sample rate, at 120bpm in 4/4 time.
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            state: Arc::new(ContextState {
+                sample_rate: AtomicU32::new(sample_rate),
+                tempo_bits: AtomicU32::new(120f32.to_bits()),
+                beats_per_bar: AtomicU32::new(4),
+                sequence: AtomicU64::new(0),
+                clock: AtomicU64::new(0),
+                beats_bits: AtomicU64::new(0f64.to_bits()),
+            }),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.state.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.state.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Tempo in beats per minute.
+    pub fn tempo(&self) -> f32 {
+        f32::from_bits(self.state.tempo_bits.load(Ordering::Relaxed))
+    }
+
+    pub fn set_tempo(&self, bpm: f32) {
+        self.state.tempo_bits.store(bpm.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn beats_per_bar(&self) -> u32 {
+        self.state.beats_per_bar.load(Ordering::Relaxed)
+    }
+
+    pub fn set_beats_per_bar(&self, beats_per_bar: u32) {
+        self.state.beats_per_bar.store(beats_per_bar.max(1), Ordering::Relaxed);
+    }
+
+    /// Number of frames consumed since the context was created.
+    pub fn now(&self) -> u64 {
+        self.state.clock.load(Ordering::Acquire)
+    }
+
+    /// Time consumed since the context was created, in seconds at the current sample rate.
+    pub fn seconds(&self) -> f64 {
+        self.now() as f64 / self.sample_rate() as f64
+    }
+
+    // beats per frame at the current tempo and sample rate
+    fn beat_rate(&self) -> f64 {
+        self.tempo() as f64 / 60.0 / self.sample_rate() as f64
+    }
+
+    // clock and the beats elapsed by it, as last stored together by `advance`
+    fn transport(&self) -> (u64, f64) {
+        loop {
+            let sequence = self.state.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let clock = self.state.clock.load(Ordering::Relaxed);
+            let beats = f64::from_bits(self.state.beats_bits.load(Ordering::Relaxed));
+            atomic::fence(Ordering::Acquire);
+            if self.state.sequence.load(Ordering::Relaxed) == sequence {
+                return (clock, beats);
+            }
+        }
+    }
+
+    /// Number of beats elapsed by the provided frame.
+    ///
+    /// Tempo changes take effect from the frame at which they are made, so beats elapsed before
+    /// a change are unaffected by it.
+    pub fn beats_at(&self, frame: u64) -> f64 {
+        let (clock, beats) = self.transport();
+        beats + (frame as f64 - clock as f64) * self.beat_rate()
+    }
+
+    /// Musical position at the current frame.
+    pub fn position(&self) -> Position {
+        Position::from_beats(self.transport().1, self.beats_per_bar())
+    }
+
+    /// Move the clock and transport forward by the provided number of frames. Called by the
+    /// `Consumer` bound to the context after filling each buffer.
+    ///
+    /// The clock and beat position are published together, so `position` and `beats_at` on other
+    /// threads never see one updated without the other.
+    pub fn advance(&self, frames: u64) {
+        let (clock, _) = self.transport();
+        let beats = self.beats_at(clock + frames);
+        let sequence = self.state.sequence.fetch_add(1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        self.state.beats_bits.store(beats.to_bits(), Ordering::Relaxed);
+        self.state.clock.store(clock + frames, Ordering::Relaxed);
+        self.state.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    /// Per-`Generator` view of the clock. See `Clock`.
+    pub fn clock(&self) -> Clock {
+        Clock { context: self.clone(), next: 0 }
+    }
+
+    /// Sine wave of the provided frequency, in phase with every other sine of the same frequency
+    /// built from this context however late it was created.
+    pub fn sine<P>(&self, frequency: P) -> Generator
+    where
+        P: Pot<f32> + 'static,
+    {
+        let context = self.clone();
+        let mut clock = self.clock();
+        let mut last_frame = None;
+        let mut phase = 0f64;
+        Box::new(move || {
+            let frame = clock.tick();
+            // frames elapsed since the last call, counting from the start of the clock at first
+            let elapsed = match last_frame {
+                Some(last) => frame - last,
+                None => frame + 1,
+            };
+            last_frame = Some(frame);
+            let step = frequency.read() as f64 / context.sample_rate() as f64;
+            phase = (phase + elapsed as f64 * step).rem_euclid(1.0);
+            (2.0 * std::f64::consts::PI * phase).sin() as Sample
+        })
+    }
+
+    /// Context equivalent of `control::pot::sine_pot`.
+    pub fn sine_pot<P>(&self, frequency: P, low: f32, high: f32) -> GeneratorPot
+    where
+        P: Pot<f32> + 'static,
+    {
+        GeneratorPot::new(
+            self.sine(frequency)
+                .compose(filter::offset(1.0))
+                .compose(filter::gain((high - low) / 2.0))
+                .compose(filter::offset(low)))
+    }
+
+    /// Context equivalent of `filter::warble`, with every warble in phase.
+    pub fn warble(&self, period: f32) -> Filter {
+        let context = self.clone();
+        let mut clock = self.clock();
+        Box::new(move |sample: Sample| {
+            let cycles = clock.tick() as f64 / (context.sample_rate() as f64 * period as f64);
+            sample * (2.0 * std::f64::consts::PI * cycles.fract()).sin() as Sample
+        })
+    }
+
+    /// Restart the provided `track` on every beat of the transport.
+    pub fn metronome<T>(&self, mut track: T) -> Generator
+    where
+        T: SampleTrack + Send + 'static,
+    {
+        let context = self.clone();
+        let mut clock = self.clock();
+        let mut last_beat = None;
+        Box::new(move || {
+            let beat = context.beats_at(clock.tick()).floor();
+            if last_beat != Some(beat) {
+                last_beat = Some(beat);
+                track.reset();
+            }
+            track.next().unwrap_or(0.0)
+        })
+    }
+
+    /// Context equivalent of `filter::band_pass`.
+    pub fn band_pass<P1, P2>(&self, center_frequency: P1, band_width: P2) -> Filter
+    where
+        P1: Pot<f64> + 'static,
+        P2: Pot<f64> + 'static,
+    {
+        let context = self.clone();
+        let rate = self.sample_rate();
+        let center_frequency =
+            OnePole::from_secs(rate, filter::DEFAULT_SMOOTHING_SECS, center_frequency);
+        let band_width = OnePole::from_secs(rate, filter::DEFAULT_SMOOTHING_SECS, band_width);
+        let mut recurse = filter::recursive_helper(3, 2);
+        Box::new(move |sample: Sample| {
+            let (a, b) = filter::band_pass_coefficients(
+                context.sample_rate(),
+                center_frequency.read(),
+                band_width.read(),
+            );
+            recurse(sample, &a, &b)
+        })
+    }
+
+    /// Context equivalent of `filter::comb`.
+    ///
+    /// The delay buffer is sized at the sample rate in effect when the filter is created, so the
+    /// echo time drifts if the sample rate changes afterwards.
+    pub fn comb(
+        &self,
+        delay_secs: f32,
+        decay_factor: f32,
+        direction: filter::CombDirection,
+    ) -> Filter {
+        filter::comb(self.sample_rate(), delay_secs, decay_factor, direction)
+    }
+}
+
+
+/// A `Generator`'s own view of the clock of an `AudioContext`.
+///
+/// The context's clock only moves once per buffer. A `Clock` counts the frames in between, and
+/// catches up with the context whenever its `Generator` hasn't been called for a while (e.g.
+/// while disconnected from a `graph::Graph`), so `tick` returns the index of the current frame
+/// as long as it is called once per frame.
+pub struct Clock {
+    context: AudioContext,
+    next: u64,
+}
+
+
+impl Clock {
+    /// Index of the current frame, moving the clock on to the next.
+    pub fn tick(&mut self) -> u64 {
+        let frame = self.next.max(self.context.now());
+        self.next = frame + 1;
+        frame
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transport() {
+        let context = AudioContext::new(100);
+        context.set_tempo(60.0);
+        context.advance(550);
+        assert_eq!(context.position(), Position { bar: 1, beat: 1, tick: TICKS_PER_BEAT / 2 });
+        assert_eq!(format!("{}", context.position()), "2.2.480");
+
+        // doubling the tempo doesn't move the beats already elapsed
+        context.set_tempo(120.0);
+        context.advance(25);
+        assert_eq!(context.position(), Position { bar: 1, beat: 2, tick: 0 });
+    }
+
+    #[test]
+    fn test_transport_consistent_across_threads() {
+        let context = AudioContext::new(44100);
+        let audio = context.clone();
+        let consumer = std::thread::spawn(move || {
+            for _ in 0 .. 100_000 {
+                audio.advance(64);
+            }
+        });
+        let beat_rate = 120.0 / 60.0 / 44100.0;
+        while context.now() < 100_000 * 64 {
+            let (clock, beats) = context.transport();
+            assert!((beats - clock as f64 * beat_rate).abs() < 1e-6);
+        }
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn test_late_sine_in_phase() {
+        let context = AudioContext::new(100);
+        let mut early = context.sine(3.0);
+        for _ in 0 .. 10 {
+            early();
+        }
+        context.advance(10);
+        let mut late = context.sine(3.0);
+        for _ in 0 .. 10 {
+            assert!((early() - late()).abs() < 1e-5);
+        }
+    }
+}
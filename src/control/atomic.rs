@@ -0,0 +1,151 @@
+//! Lock-free `Pot`s for handing values from control threads to the audio thread.
+//!
+//! An `AtomicPot` and its `AtomicPotWriter` share a single atomic value: writes from any thread
+//! replace it and every `read` loads it, without locks, channels or allocation. Only the latest
+//! value is kept, which is exactly what a knob or fader needs. Sharing a `Pot` through
+//! `Arc<Mutex<P>>` instead risks the audio thread waiting on a control thread holding the lock.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::Pot;
+
+
+/// Value that can be stored in an `AtomicPot`: `f32`, `f64` or `bool`.
+pub trait AtomicValue: Copy + Send + Sync + 'static {
+    /// Atomic storage for the value.
+    type Storage: Send + Sync;
+
+    fn new_storage(value: Self) -> Self::Storage;
+    fn load(storage: &Self::Storage) -> Self;
+    fn store(storage: &Self::Storage, value: Self);
+}
+
+
+// floats are stored as their bit patterns
+impl AtomicValue for f32 {
+    type Storage = AtomicU32;
+
+    fn new_storage(value: Self) -> AtomicU32 {
+        AtomicU32::new(value.to_bits())
+    }
+
+    fn load(storage: &AtomicU32) -> Self {
+        f32::from_bits(storage.load(Ordering::Relaxed))
+    }
+
+    fn store(storage: &AtomicU32, value: Self) {
+        storage.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+
+impl AtomicValue for f64 {
+    type Storage = AtomicU64;
+
+    fn new_storage(value: Self) -> AtomicU64 {
+        AtomicU64::new(value.to_bits())
+    }
+
+    fn load(storage: &AtomicU64) -> Self {
+        f64::from_bits(storage.load(Ordering::Relaxed))
+    }
+
+    fn store(storage: &AtomicU64, value: Self) {
+        storage.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+
+impl AtomicValue for bool {
+    type Storage = AtomicBool;
+
+    fn new_storage(value: Self) -> AtomicBool {
+        AtomicBool::new(value)
+    }
+
+    fn load(storage: &AtomicBool) -> Self {
+        storage.load(Ordering::Relaxed)
+    }
+
+    fn store(storage: &AtomicBool, value: Self) {
+        storage.store(value, Ordering::Relaxed);
+    }
+}
+
+
+/// Reading end of an atomic value, for the audio thread. `read` is wait-free.
+pub struct AtomicPot<T: AtomicValue> {
+    storage: Arc<T::Storage>,
+}
+
+
+/// Writing end of an atomic value, for control threads.
+pub struct AtomicPotWriter<T: AtomicValue> {
+    storage: Arc<T::Storage>,
+}
+
+
+/// Create a linked `AtomicPot` and `AtomicPotWriter` holding the `initial` value.
+///
+/// Both ends can be cloned to read or write the same value from more places.
+pub fn atomic_pot<T: AtomicValue>(initial: T) -> (AtomicPot<T>, AtomicPotWriter<T>) {
+    let storage = Arc::new(T::new_storage(initial));
+    (AtomicPot { storage: Arc::clone(&storage) }, AtomicPotWriter { storage })
+}
+
+
+impl<T: AtomicValue> Clone for AtomicPot<T> {
+    fn clone(&self) -> Self {
+        Self { storage: Arc::clone(&self.storage) }
+    }
+}
+
+
+impl<T: AtomicValue> Clone for AtomicPotWriter<T> {
+    fn clone(&self) -> Self {
+        Self { storage: Arc::clone(&self.storage) }
+    }
+}
+
+
+impl<T: AtomicValue> AtomicPotWriter<T> {
+    /// Replace the value seen by the linked `AtomicPot`s.
+    pub fn set(&self, value: T) {
+        T::store(&self.storage, value);
+    }
+
+    pub fn get(&self) -> T {
+        T::load(&self.storage)
+    }
+
+    /// Create another `AtomicPot` reading the value written here.
+    pub fn pot(&self) -> AtomicPot<T> {
+        AtomicPot { storage: Arc::clone(&self.storage) }
+    }
+}
+
+
+impl<T: AtomicValue> Pot<T> for AtomicPot<T> {
+    fn read(&self) -> T {
+        T::load(&self.storage)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_across_threads() {
+        let (pot, writer) = atomic_pot(0.25f32);
+        assert_eq!(pot.read(), 0.25);
+        std::thread::spawn(move || writer.set(-3.5)).join().unwrap();
+        assert_eq!(pot.read(), -3.5);
+
+        let (flag, flag_writer) = atomic_pot(false);
+        flag_writer.set(true);
+        assert!(flag.read());
+    }
+}
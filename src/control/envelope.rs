@@ -0,0 +1,89 @@
+//! Envelope followers: `Pot`s that read the loudness of a `Generator`.
+//!
+//! Following one stream's level and feeding it to another's parameters (a filter cutoff, a gain)
+//! enables sidechain-style modulation through the ordinary `Pot` plumbing. Use
+//! `control::flow::fork` to follow a stream that should also be heard.
+
+use std::cell::RefCell;
+
+use crate::{Generator, Pot};
+use crate::filter::dynamics::{Ballistics, Envelope};
+
+
+/// How an `EnvelopeFollower` measures level.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Detection {
+    /// Follow the rectified waveform. Responds to transients, good for triggering effects.
+    Peak,
+    /// Follow the mean square of the waveform. Closer to perceived loudness, good for ducking.
+    Rms,
+}
+
+
+/// Reads the level of the held `Generator` on `[0, 1]` (for full-scale input), advancing it by one
+/// sample per read.
+pub struct EnvelopeFollower {
+    gen: RefCell<Generator>,
+    envelope: RefCell<Envelope>,
+    detection: Detection,
+}
+
+
+impl EnvelopeFollower {
+    pub fn new(
+        sample_rate: u32,
+        detection: Detection,
+        ballistics: Ballistics,
+        generator: Generator,
+    ) -> Self {
+        Self {
+            gen: RefCell::new(generator),
+            envelope: RefCell::new(Envelope::new(sample_rate, ballistics)),
+            detection,
+        }
+    }
+}
+
+
+impl Pot<f32> for EnvelopeFollower {
+    fn read(&self) -> f32 {
+        let sample = (*self.gen.borrow_mut())();
+        let mut envelope = self.envelope.borrow_mut();
+        match self.detection {
+            Detection::Peak => envelope.track(sample.abs()),
+            Detection::Rms => envelope.track(sample * sample).sqrt(),
+        }
+    }
+}
+
+
+impl Pot<f64> for EnvelopeFollower {
+    fn read(&self) -> f64 {
+        let r: f32 = self.read();
+        r as f64
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    #[test]
+    fn test_follow_sine() {
+        let ballistics = Ballistics::new(0.001, 0.05);
+        let peak = EnvelopeFollower::new(
+            44100, Detection::Peak, ballistics, generator::sine(44100, 100.0));
+        let rms = EnvelopeFollower::new(
+            44100, Detection::Rms, Ballistics::new(0.05, 0.05), generator::sine(44100, 100.0));
+        for _ in 0 .. 44100 {
+            let _: f32 = peak.read();
+            let _: f32 = rms.read();
+        }
+        let peak_level: f32 = peak.read();
+        let rms_level: f32 = rms.read();
+        assert!(peak_level > 0.8 && peak_level <= 1.0);
+        assert!((rms_level - 0.5f32.sqrt()).abs() < 0.05);
+    }
+}
@@ -42,3 +42,66 @@ where
         ((2.0 - b_val) * 0.5 * l_val) + (b_val * 0.5 * r_val)
     })
 }
+
+
+/// Ring modulate the two `Generator`s by multiplying them sample by sample.
+///
+/// The output contains the sum and difference frequencies of the inputs' spectra, but neither
+/// input itself.
+pub fn ring_modulate(mut carrier: Generator, mut modulator: Generator) -> Generator {
+    Box::new(move || carrier() * modulator())
+}
+
+
+/// Amplitude modulate the `carrier` with the `modulator`.
+///
+/// A `depth` of 0 leaves the `carrier` untouched and a `depth` of 1 swings its amplitude fully
+/// between zero and its peak as the `modulator` (expected on `[-1, 1]`) moves. Unlike ring
+/// modulation, the `carrier` remains audible alongside the sidebands.
+pub fn amplitude_modulate<P>(
+    depth: P,
+    mut carrier: Generator,
+    mut modulator: Generator,
+) -> Generator
+where
+    P: Pot<f32> + 'static,
+{
+    Box::new(move || {
+        let d = depth.read();
+        carrier() * (1.0 - d + d * 0.5 * (modulator() + 1.0))
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Sample;
+
+    fn ramp(step: Sample) -> Generator {
+        let mut value = 0.0;
+        Box::new(move || {
+            value += step;
+            value
+        })
+    }
+
+    #[test]
+    fn test_ring_modulate() {
+        let mut ring = ring_modulate(ramp(0.5), ramp(-0.25));
+        let mut carrier = ramp(0.5);
+        let mut modulator = ramp(-0.25);
+        for _ in 0 .. 10 {
+            assert_eq!(ring(), carrier() * modulator());
+        }
+    }
+
+    #[test]
+    fn test_amplitude_modulate_without_depth() {
+        let mut modulated = amplitude_modulate(0.0, ramp(0.1), generator::sine(44100, 5.0));
+        let mut carrier = ramp(0.1);
+        for _ in 0 .. 100 {
+            assert_eq!(modulated(), carrier());
+        }
+    }
+}
@@ -5,3 +5,6 @@ pub mod pot;
 pub mod mux;
 pub mod flow;
 pub mod key;
+pub mod smooth;
+pub mod envelope;
+pub mod atomic;
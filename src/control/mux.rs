@@ -1,10 +1,105 @@
 //! Various ways to multiplex streams.
 
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use crate::{Generator, Sample, Pot};
 
 
+/// Number of frames generated by `muxn` each time an output runs out of buffered values.
+const MUX_BLOCK_FRAMES: usize = 64;
+
+
+/// Number of frames `muxn` buffers for an output that has fallen behind before dropping the
+/// oldest ones, enough to cover a generous device buffer filled one channel at a time.
+///
+/// Queues are allocated at this size upfront so that the audio thread never has to grow them.
+const MUX_MAX_LAG_FRAMES: usize = 16384;
+
+
+// state shared by all outputs of `muxn`
+struct MuxState<F> {
+    inputs: Vec<Generator>,
+    mux_function: F,
+    input_frame: Vec<Sample>,
+    output_frame: Vec<Sample>,
+    queues: Vec<VecDeque<Sample>>,
+}
+
+
+impl<F> MuxState<F>
+where
+    F: FnMut(&[Sample], &mut [Sample]),
+{
+    // move the next block of values for `output` into `buffer`, generating frames as necessary
+    fn pull(&mut self, output: usize, buffer: &mut Vec<Sample>) {
+        if self.queues[output].is_empty() {
+            for _ in 0 .. MUX_BLOCK_FRAMES {
+                for (value, input) in self.input_frame.iter_mut().zip(self.inputs.iter_mut()) {
+                    *value = input();
+                }
+                for value in self.output_frame.iter_mut() {
+                    *value = 0.0;
+                }
+                (self.mux_function)(&self.input_frame, &mut self.output_frame);
+                for (queue, &value) in self.queues.iter_mut().zip(self.output_frame.iter()) {
+                    if queue.len() == MUX_MAX_LAG_FRAMES {
+                        queue.pop_front();
+                    }
+                    queue.push_back(value);
+                }
+            }
+        }
+        let n = self.queues[output].len().min(MUX_BLOCK_FRAMES);
+        buffer.clear();
+        buffer.extend(self.queues[output].drain(.. n));
+    }
+}
+
+
+/// Multiplex any number of input streams into any number of output streams using a custom mux
+/// function.
+///
+/// For every frame, the `mux_function` is handed the current value of each of the `inputs` and
+/// writes the value of each of the `n_outputs` outputs (initially zeroed), e.g. a mixing matrix.
+///
+/// The yielded `Generator`s are entangled in that calling one also advances the others. Each
+/// output yields its values frame by frame in order however the calls to the outputs are
+/// interleaved, so it is fine for a `Consumer` to fill a whole buffer from one output before
+/// moving on to the next. An output that falls more than 16384 frames behind the others skips
+/// ahead, losing the oldest frames. Frames are generated 64 at a time so that the outputs only
+/// lock their shared state once per block rather than on every sample.
+pub fn muxn<F>(mux_function: F, inputs: Vec<Generator>, n_outputs: usize) -> Vec<Generator>
+where
+    F: FnMut(&[Sample], &mut [Sample]) + Send + 'static,
+{
+    let state = Arc::new(Mutex::new(MuxState {
+        input_frame: vec![0.0; inputs.len()],
+        output_frame: vec![0.0; n_outputs],
+        queues: (0 .. n_outputs).map(|_| VecDeque::with_capacity(MUX_MAX_LAG_FRAMES)).collect(),
+        inputs,
+        mux_function,
+    }));
+
+    (0 .. n_outputs)
+        .map(|output| {
+            let state = Arc::clone(&state);
+            let mut buffer: Vec<Sample> = Vec::with_capacity(MUX_BLOCK_FRAMES);
+            let mut position = 0;
+            let out: Generator = Box::new(move || {
+                if position == buffer.len() {
+                    state.lock().unwrap().pull(output, &mut buffer);
+                    position = 0;
+                }
+                position += 1;
+                buffer[position - 1]
+            });
+            out
+        })
+        .collect()
+}
+
+
 /// Multiplex together left/right streams in a stereo setup using a custom mux function.
 ///
 /// The muxing is performed by the provided `mux_function` that determines how much of each
@@ -12,64 +107,25 @@ use crate::{Generator, Sample, Pot};
 ///
 /// The yielded `Generator`s are entangled in that calling one also calls the other. This is
 /// important to take note of for `Generator` implementations that keep some sort of internal state.
+/// See `muxn` for details.
 pub fn mux2<F>(
-    mux_function: F,
+    mut mux_function: F,
     left: Generator,
     right: Generator,
 ) -> (Generator, Generator)
 where
     F: FnMut(Sample, Sample) -> (Sample, Sample) + Send + 'static,
 {
-    // TODO: use single Arc for whole shared state, instead of 3 Arcs for the three components
-    // that are shared?
-    let vals_left = Arc::new(Mutex::new((None::<Sample>, None::<Sample>)));
-    let vals_right = Arc::clone(&vals_left);
-
-    let generators_left = Arc::new(Mutex::new((left, right)));
-    let generators_right = Arc::clone(&generators_left);
-
-    let mux_f_left = Arc::new(Mutex::new(mux_function));
-    let mux_f_right = Arc::clone(&mux_f_left);
-
-    let out_left: Generator = Box::new(move || {
-        let mut vals_unlocked = vals_left.lock().unwrap();
-        match *vals_unlocked {
-            (Some(_), Some(_)) => unreachable!("neither value collected -- should never occur"),
-            (Some(l), None) => {
-                *vals_unlocked = (None, None);
-                l
-            },
-            (None, _) => {
-                let (ref mut left_gen, ref mut right_gen) = &mut *generators_left.lock().unwrap();
-                let ref mut mux_f = &mut *mux_f_left.lock().unwrap();
-                let (l, r) = mux_f(left_gen(), right_gen());
-                *vals_unlocked = (None, Some(r));
-                l
-            },
-        }
-    });
-
-    // this would get tedious for more than two channels -- is there a general-form solution for
-    // this multi-stream muxing problem?
-    let out_right: Generator = Box::new(move || {
-        let mut vals_unlocked = vals_right.lock().unwrap();
-        match *vals_unlocked {
-            (Some(_), Some(_)) => unreachable!("neither value collected -- should never occur"),
-            (None, Some(r)) => {
-                *vals_unlocked = (None, None);
-                r
-            },
-            (_, None) => {
-                let (ref mut left_gen, ref mut right_gen) = &mut *generators_right.lock().unwrap();
-                let ref mut mux_f = &mut *mux_f_right.lock().unwrap();
-                let (l, r) = mux_f(left_gen(), right_gen());
-                *vals_unlocked = (Some(l), None);
-                r
-            },
-        }
-    });
-
-    (out_left, out_right)
+    let mux_frame = move |inputs: &[Sample], outputs: &mut [Sample]| {
+        let (l, r) = mux_function(inputs[0], inputs[1]);
+        outputs[0] = l;
+        outputs[1] = r;
+    };
+    let mut outputs = muxn(mux_frame, vec![left, right], 2).into_iter();
+    match (outputs.next(), outputs.next()) {
+        (Some(out_left), Some(out_right)) => (out_left, out_right),
+        _ => unreachable!("`muxn` yields exactly as many outputs as requested"),
+    }
 }
 
 
@@ -97,3 +153,59 @@ where
     };
     mux2(balance_fun, left, right)
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counter() -> Generator {
+        let mut count = 0f32;
+        Box::new(move || {
+            count += 1.0;
+            count
+        })
+    }
+
+    #[test]
+    fn test_muxn_interleaving() {
+        // sum and difference of the inputs, read one whole buffer per output at a time
+        let sum_difference = |inputs: &[Sample], outputs: &mut [Sample]| {
+            outputs[0] = inputs[0] + inputs[1];
+            outputs[1] = inputs[0] - inputs[1];
+            outputs[2] = inputs[0];
+        };
+        let mut outputs = muxn(sum_difference, vec![counter(), counter()], 3);
+        let sums: Vec<Sample> = (0 .. 100).map(|_| outputs[0]()).collect();
+        let differences: Vec<Sample> = (0 .. 100).map(|_| outputs[1]()).collect();
+        let firsts: Vec<Sample> = (0 .. 100).map(|_| outputs[2]()).collect();
+        for i in 0 .. 100 {
+            assert_eq!(sums[i], 2.0 * (i + 1) as f32);
+            assert_eq!(differences[i], 0.0);
+            assert_eq!(firsts[i], (i + 1) as f32);
+        }
+    }
+
+    #[test]
+    fn test_muxn_lagging_output_does_not_grow_queues() {
+        let mut state = MuxState {
+            inputs: vec![counter()],
+            mux_function: |inputs: &[Sample], outputs: &mut [Sample]| {
+                outputs[0] = inputs[0];
+                outputs[1] = inputs[0];
+            },
+            input_frame: vec![0.0],
+            output_frame: vec![0.0; 2],
+            queues: (0 .. 2).map(|_| VecDeque::with_capacity(MUX_MAX_LAG_FRAMES)).collect(),
+        };
+        let capacity = state.queues[1].capacity();
+        let mut buffer = Vec::with_capacity(MUX_BLOCK_FRAMES);
+        for _ in 0 .. 2 * MUX_MAX_LAG_FRAMES / MUX_BLOCK_FRAMES {
+            state.pull(0, &mut buffer);
+        }
+        assert_eq!(state.queues[1].len(), MUX_MAX_LAG_FRAMES);
+        assert_eq!(state.queues[1].capacity(), capacity);
+        state.pull(1, &mut buffer);
+        assert_eq!(buffer[0], (MUX_MAX_LAG_FRAMES + 1) as Sample);
+    }
+}
@@ -1,12 +1,13 @@
 //! `Pot` trait implementations.
 
-use std::cell::{RefCell, Cell};
-use std::sync::{mpsc, Arc, Mutex};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use anyhow::Result;
 
 use crate::{generator, filter, Pot, Generator, FilterComposable};
+use crate::control::atomic::{atomic_pot, AtomicPot, AtomicValue};
 
 
 /// Allow for the usage of raw floats as `f32` potentiometers when control over the value is not
@@ -75,9 +76,10 @@ where
 
 
 /// Interactively read values from stdin via `readline`.
-pub struct StdinPot<T> {
-    cur: Cell<T>,
-    receiver: mpsc::Receiver<T>,
+///
+/// Values entered are handed to the audio thread through an `AtomicPot`.
+pub struct StdinPot<T: AtomicValue> {
+    pot: AtomicPot<T>,
 }
 
 
@@ -97,7 +99,7 @@ impl Default for StdinPot<f64> {
 
 impl<T> StdinPot<T>
 where
-    T: Send + 'static,
+    T: AtomicValue,
 {
     /// Create a new `StdinPot`, spawning a stdin reader thread.
     pub fn new<F>(name: &str, default: T, converter: F) -> Self
@@ -106,7 +108,7 @@ where
     {
         let prompt = format!("{}> ", name);
         let mut reader = rustyline::Editor::<()>::new();
-        let (sender, receiver) = mpsc::channel();
+        let (pot, writer) = atomic_pot(default);
         thread::spawn(move || loop {
             match reader.readline(prompt.as_str()) {
                 Ok(l) if l == "q" => {
@@ -116,7 +118,7 @@ where
                 Ok(l) => {
                     match converter(l.as_str()) {
                         Ok(val) => {
-                            sender.send(val).unwrap();  // thread panic if channel is closed
+                            writer.set(val);
                             reader.add_history_entry(l.as_str());
                         },
                         Err(e) => eprintln!("unable to parse '{}', try again (reason: {:?})", l, e),
@@ -128,31 +130,25 @@ where
                 },
             }
         });
-        Self {
-            cur: Cell::new(default),
-            receiver: receiver,
-        }
+        Self { pot }
     }
 }
 
 
 impl<T> Pot<T> for StdinPot<T>
 where
-    T: Send + Copy + 'static,
+    T: AtomicValue,
 {
     fn read(&self) -> T {
-        match self.receiver.try_recv() {
-            Ok(val) => {
-                self.cur.set(val);
-                val
-            },
-            Err(_) => self.cur.get(),
-        }
+        self.pot.read()
     }
 }
 
 
 /// Enables sharing of a `Pot` impl in multiple places.
+///
+/// Prefer `control::atomic::AtomicPot` for values written from another thread: a control thread
+/// holding this lock stalls the audio thread.
 impl<T, P> Pot<T> for Arc<Mutex<P>>
 where
     T: Send + Copy + 'static,
@@ -0,0 +1,195 @@
+//! Smoothing wrappers that take the edge off of sudden jumps in a `Pot`'s value.
+//!
+//! A `Pot` updated from a control thread (`StdinPot`, `ThreadedI2cPot`, a USB knob) changes in
+//! steps, and applying a step to a gain or a filter coefficient mid-waveform is heard as a click
+//! or zipper noise. Each wrapper here advances by one step per `read`, so it assumes it is read
+//! once per sample, as it is by every `Generator` and `Filter` in this crate.
+//!
+//! Smoothers start out at the first value read from the wrapped `Pot`, so smoothing a constant
+//! has no effect.
+
+use std::cell::Cell;
+
+use crate::Pot;
+
+
+/// Exponential (one-pole low-pass) smoothing.
+///
+/// Covers ~63% of the distance to a new value after `steps` reads and never quite arrives, which
+/// sounds natural for gains and cutoffs.
+pub struct OnePole<P> {
+    pot: P,
+    coefficient: f64,
+    current: Cell<Option<f64>>,
+}
+
+
+impl<P> OnePole<P> {
+    pub fn new(pot: P, steps: f32) -> Self {
+        let coefficient = if steps <= 0.0 { 0.0 } else { (-1.0 / steps as f64).exp() };
+        Self {
+            pot,
+            coefficient,
+            current: Cell::new(None),
+        }
+    }
+
+    /// Smooth with a time constant of `secs` seconds at the provided sample rate.
+    pub fn from_secs(sample_rate: u32, secs: f32, pot: P) -> Self {
+        Self::new(pot, secs * sample_rate as f32)
+    }
+
+    fn step(&self, target: f64) -> f64 {
+        let next = match self.current.get() {
+            Some(current) => target + self.coefficient * (current - target),
+            None => target,
+        };
+        self.current.set(Some(next));
+        next
+    }
+}
+
+
+/// Linear ramp to each new value over a fixed number of reads.
+///
+/// Arrives exactly after `steps` reads, which suits parameters where the length of the
+/// transition matters more than its shape, e.g. crossfades.
+pub struct LinearRamp<P> {
+    pot: P,
+    steps: u32,
+    // (current value, target, increment per step, steps remaining)
+    state: Cell<Option<(f64, f64, f64, u32)>>,
+}
+
+
+impl<P> LinearRamp<P> {
+    pub fn new(pot: P, steps: u32) -> Self {
+        Self {
+            pot,
+            steps: steps.max(1),
+            state: Cell::new(None),
+        }
+    }
+
+    /// Ramp over `secs` seconds at the provided sample rate.
+    pub fn from_secs(sample_rate: u32, secs: f32, pot: P) -> Self {
+        Self::new(pot, (secs * sample_rate as f32).round() as u32)
+    }
+
+    fn step(&self, target: f64) -> f64 {
+        let (mut current, mut ramp_target, mut increment, mut remaining) = match self.state.get() {
+            Some(state) => state,
+            None => (target, target, 0.0, 0),
+        };
+        if target != ramp_target {
+            ramp_target = target;
+            remaining = self.steps;
+            increment = (target - current) / self.steps as f64;
+        }
+        if remaining > 0 {
+            remaining -= 1;
+            current = if remaining == 0 { ramp_target } else { current + increment };
+        }
+        self.state.set(Some((current, ramp_target, increment, remaining)));
+        current
+    }
+}
+
+
+/// Slew-rate limiter: the value may change by at most `max_step` per read.
+///
+/// Small changes pass through immediately while large jumps are turned into constant-rate
+/// glides, like the portamento on a monophonic synth.
+pub struct SlewLimiter<P> {
+    pot: P,
+    max_step: f64,
+    current: Cell<Option<f64>>,
+}
+
+
+impl<P> SlewLimiter<P> {
+    pub fn new(pot: P, max_step: f64) -> Self {
+        Self {
+            pot,
+            max_step: max_step.abs(),
+            current: Cell::new(None),
+        }
+    }
+
+    /// Limit changes to `units_per_sec` at the provided sample rate.
+    pub fn from_rate(sample_rate: u32, units_per_sec: f64, pot: P) -> Self {
+        Self::new(pot, units_per_sec / sample_rate as f64)
+    }
+
+    fn step(&self, target: f64) -> f64 {
+        let next = match self.current.get() {
+            Some(current) => current + (target - current).max(-self.max_step).min(self.max_step),
+            None => target,
+        };
+        self.current.set(Some(next));
+        next
+    }
+}
+
+
+macro_rules! impl_smoothed_pot {
+    ($smoother:ident, $t:ty) => {
+        impl<P> Pot<$t> for $smoother<P>
+        where
+            P: Pot<$t>,
+        {
+            fn read(&self) -> $t {
+                self.step(self.pot.read() as f64) as $t
+            }
+        }
+    };
+}
+
+
+impl_smoothed_pot!(OnePole, f32);
+impl_smoothed_pot!(OnePole, f64);
+impl_smoothed_pot!(LinearRamp, f32);
+impl_smoothed_pot!(LinearRamp, f64);
+impl_smoothed_pot!(SlewLimiter, f32);
+impl_smoothed_pot!(SlewLimiter, f64);
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::pot::GeneratorPot;
+
+    // pot that jumps from 0 to 1 after its first read
+    fn step_pot() -> GeneratorPot {
+        let mut first = true;
+        GeneratorPot::new(Box::new(move || {
+            if first {
+                first = false;
+                0.0
+            } else {
+                1.0
+            }
+        }))
+    }
+
+    #[test]
+    fn test_constant_unaffected() {
+        let smoothed = OnePole::new(0.5f32, 100.0);
+        assert_eq!(smoothed.read(), 0.5);
+        assert_eq!(smoothed.read(), 0.5);
+    }
+
+    #[test]
+    fn test_linear_ramp() {
+        let ramp = LinearRamp::new(step_pot(), 4);
+        let values: Vec<f32> = (0 .. 6).map(|_| ramp.read()).collect();
+        assert_eq!(values, vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_slew_limiter() {
+        let slew = SlewLimiter::new(step_pot(), 0.4);
+        let values: Vec<f32> = (0 .. 5).map(|_| slew.read()).collect();
+        assert_eq!(values, vec![0.0, 0.4, 0.8, 1.0, 1.0]);
+    }
+}
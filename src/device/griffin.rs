@@ -7,28 +7,27 @@
 //! See `hardware/griffin/README.md` for hacking notes to get this device working on a normal Linux
 //! and Tegra Linux (L4T) machine.
 
-use std::cell::Cell;
 use std::fs::File;
-use std::sync::mpsc;
+use std::ops::{Add, Neg};
 use std::thread;
 use std::io::Read;
 
 use anyhow::Result;
 
 use crate::Pot;
+use crate::control::atomic::{atomic_pot, AtomicPot, AtomicValue};
 
 
-pub struct PowerMateUsbPot<T> {
-    receiver: mpsc::Receiver<T>,
-    latest: Cell<T>,
-    min: T,
-    max: T,
+/// Knob position accumulated from the device's increments on a background thread and handed to
+/// the audio thread through an `AtomicPot`.
+pub struct PowerMateUsbPot<T: AtomicValue> {
+    pot: AtomicPot<T>,
 }
 
 
 impl<T> PowerMateUsbPot<T>
 where
-    T: std::ops::Neg<Output = T> + Copy + Send + 'static,
+    T: AtomicValue + Neg<Output = T> + Add<Output = T> + PartialOrd,
 {
     /// Length of events yielded by the device.
     ///
@@ -42,40 +41,35 @@ where
     pub fn new(start: T, min: T, max: T, inc: T) -> Result<Self> {
         let mut file = File::open(Self::DEVICE_PATH)?;
         let mut buffer = vec![0u8; Self::EVENT_LEN];
-        let (sender, receiver) = mpsc::channel();
+        let (pot, writer) = atomic_pot(start);
 
         thread::spawn(move || loop {
             file.read(buffer.as_mut_slice()).expect("PowerMate device disappeared");
             // TODO: is it worth the extra baggage to integrate software that understands input.h
             // events a little better than this?
-            match (buffer[16], buffer[20]) {
-                (0x02, 0xFF) => sender.send(-inc).expect("PowerMateUsb channel closed"),
-                (0x02, 0x01) => sender.send(inc).expect("PowerMateUsb channel closed"),
-                (0x02, _) => eprintln!("unexpected message received"),
-                _ => (),
+            let step = match (buffer[16], buffer[20]) {
+                (0x02, 0xFF) => -inc,
+                (0x02, 0x01) => inc,
+                (0x02, _) => {
+                    eprintln!("unexpected message received");
+                    continue;
+                },
+                _ => continue,
             };
+            let turned = writer.get() + step;
+            writer.set(if turned < min { min } else if turned > max { max } else { turned });
         });
 
-        Ok(Self {
-            receiver,
-            latest: Cell::new(start),
-            min,
-            max,
-        })
+        Ok(Self { pot })
     }
 }
 
 
-impl Pot<f32> for PowerMateUsbPot<f32> {
-    fn read(&self) -> f32 {
-        let latest = self.latest.get();
-        match self.receiver.try_recv() {
-            Ok(inc_val) => {
-                let new = (latest + inc_val).max(self.min).min(self.max);
-                self.latest.set(new);
-                new
-            },
-            Err(_) => latest,
-        }
+impl<T> Pot<T> for PowerMateUsbPot<T>
+where
+    T: AtomicValue,
+{
+    fn read(&self) -> T {
+        self.pot.read()
     }
 }
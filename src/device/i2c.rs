@@ -1,5 +1,4 @@
-use std::cell::{RefCell, Cell};
-use std::sync::mpsc;
+use std::cell::RefCell;
 use std::thread;
 
 use anyhow::Result;
@@ -7,6 +6,7 @@ use embedded_hal::blocking::i2c::Read;
 use linux_embedded_hal::I2cdev;
 
 use crate::Pot;
+use crate::control::atomic::{atomic_pot, AtomicPot, AtomicValue};
 
 
 pub struct BlockingI2cPot<T> {
@@ -51,15 +51,16 @@ impl<T> Pot<T> for BlockingI2cPot<T> {
 }
 
 
-pub struct ThreadedI2cPot<T: Sized + Send + Copy> {
-    receiver: mpsc::Receiver<T>,
-    latest: Cell<T>,
+/// Polls a `BlockingI2cPot` on a background thread, handing each reading to the audio thread
+/// through an `AtomicPot`.
+pub struct ThreadedI2cPot<T: AtomicValue> {
+    pot: AtomicPot<T>,
 }
 
 
 impl<T> ThreadedI2cPot<T>
 where
-    T: Sized + Send + Copy + 'static, // static required for thread::spawn
+    T: AtomicValue,
 {
     pub fn new<F>(
         bus: u8,
@@ -70,34 +71,21 @@ where
     where
         F: Fn(&[u8]) -> T + Send + 'static,
     {
-        let (sender, receiver) = mpsc::channel();
-
         let blocking_pot = BlockingI2cPot::new(bus, address, message_size, converter)?;
+        let (pot, writer) = atomic_pot(blocking_pot.read());
         thread::spawn(move || loop {
-            let new_val = blocking_pot.read();
-            sender.send(new_val).expect("channel closed");
+            writer.set(blocking_pot.read());
         });
-
-        let first_val = receiver.recv()?;
-        Ok(ThreadedI2cPot {
-            receiver: receiver,
-            latest: Cell::new(first_val),
-        })
+        Ok(ThreadedI2cPot { pot })
     }
 }
 
 
 impl<T> Pot<T> for ThreadedI2cPot<T>
 where
-    T: Sized + Send + Copy,
+    T: AtomicValue,
 {
     fn read(&self) -> T {
-        match self.receiver.try_recv() {
-            Ok(val) => {
-                self.latest.set(val);
-                val
-            },
-            Err(_) => self.latest.get(),
-        }
+        self.pot.read()
     }
 }
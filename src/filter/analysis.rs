@@ -0,0 +1,355 @@
+//! Frequency response and stability analysis of `Filter`s.
+//!
+//! Recursive filters in the form taken by `filter::recursive` can be analyzed exactly from their
+//! coefficients with a `TransferFunction`, which can also reject unstable settings before they
+//! ever reach an audio stream. Any other `Filter` can be measured from its impulse response with
+//! `measure`.
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+use anyhow::{anyhow, Result};
+
+use crate::Filter;
+
+
+/// Minimal complex number used to describe poles, zeros and responses.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    /// Point on the unit circle at the provided angle, `e^(iθ)`.
+    pub fn from_angle(theta: f64) -> Self {
+        Self::new(theta.cos(), theta.sin())
+    }
+
+    /// Magnitude (distance from the origin).
+    pub fn norm(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// Angle from the positive real axis, on `(-π, π]`.
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn conj(&self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    /// Multiply by a real scalar.
+    pub fn scale(&self, factor: f64) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.im < 0.0 {
+            write!(f, "{:.4}-{:.4}i", self.re, -self.im)
+        } else {
+            write!(f, "{:.4}+{:.4}i", self.re, self.im)
+        }
+    }
+}
+
+
+impl Add for Complex {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+
+impl Sub for Complex {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+
+impl Mul for Complex {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+
+impl Div for Complex {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        let denom = other.re.powi(2) + other.im.powi(2);
+        (self * other.conj()).scale(1.0 / denom)
+    }
+}
+
+
+impl Neg for Complex {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.re, -self.im)
+    }
+}
+
+
+/// Magnitude and phase of a filter's response at a set of frequencies.
+#[derive(Debug, Clone)]
+pub struct FrequencyResponse {
+    /// Frequencies (Hz) at which the response was evaluated.
+    pub frequencies: Vec<f64>,
+    /// Linear gain at each frequency.
+    pub magnitude: Vec<f64>,
+    /// Phase shift at each frequency, in radians on `(-π, π]`.
+    pub phase: Vec<f64>,
+}
+
+
+impl FrequencyResponse {
+    fn from_complex(frequencies: &[f64], response: Vec<Complex>) -> Self {
+        Self {
+            frequencies: frequencies.to_vec(),
+            magnitude: response.iter().map(|h| h.norm()).collect(),
+            phase: response.iter().map(|h| h.arg()).collect(),
+        }
+    }
+
+    /// Gain at each frequency, in decibels.
+    pub fn magnitude_db(&self) -> Vec<f64> {
+        self.magnitude.iter().map(|m| 20.0 * m.max(1e-12).log10()).collect()
+    }
+
+    /// Frequency and gain of the loudest point in the response.
+    pub fn peak(&self) -> Option<(f64, f64)> {
+        self.frequencies
+            .iter()
+            .zip(self.magnitude.iter())
+            .fold(None, |best: Option<(f64, f64)>, (&f, &m)| match best {
+                Some((_, best_m)) if best_m >= m => best,
+                _ => Some((f, m)),
+            })
+    }
+}
+
+
+/// `n` frequencies evenly spaced from DC up to (excluding) the Nyquist frequency.
+pub fn linear_frequencies(sample_rate: u32, n: usize) -> Vec<f64> {
+    let nyquist = sample_rate as f64 / 2.0;
+    (0 .. n).map(|i| nyquist * i as f64 / n as f64).collect()
+}
+
+
+/// `n` frequencies spaced logarithmically from `low` to `high` Hz inclusive.
+pub fn log_frequencies(low: f64, high: f64, n: usize) -> Vec<f64> {
+    if n < 2 {
+        return vec![low];
+    }
+    let step = (high / low).powf(1.0 / (n - 1) as f64);
+    (0 .. n).map(|i| low * step.powi(i as i32)).collect()
+}
+
+
+// evaluate sum(coeffs[k] * z^-k) at the provided point
+fn evaluate_inverse(coeffs: &[f64], z_inverse: Complex) -> Complex {
+    coeffs.iter().rev().fold(Complex::default(), |acc, &c| acc * z_inverse + Complex::new(c, 0.0))
+}
+
+
+// roots of the polynomial with the provided coefficients, highest power first
+//
+// Solved with the Durand-Kerner method, which converges on all roots simultaneously.
+fn polynomial_roots(coeffs: &[f64]) -> Vec<Complex> {
+    let leading = coeffs.iter().position(|c| *c != 0.0);
+    let coeffs = match leading {
+        Some(i) => &coeffs[i ..],
+        None => return vec![],
+    };
+    let degree = coeffs.len() - 1;
+    let monic: Vec<f64> = coeffs.iter().map(|c| c / coeffs[0]).collect();
+    let evaluate = |z: Complex| {
+        monic.iter().fold(Complex::default(), |acc, &c| acc * z + Complex::new(c, 0.0))
+    };
+
+    let seed = Complex::new(0.4, 0.9);
+    let mut roots: Vec<Complex> = (0 .. degree)
+        .scan(Complex::new(1.0, 0.0), |power, _| {
+            *power = *power * seed;
+            Some(*power)
+        })
+        .collect();
+
+    for _ in 0 .. 1000 {
+        let mut largest_step = 0f64;
+        for i in 0 .. degree {
+            let denom = (0 .. degree)
+                .filter(|&j| j != i)
+                .fold(Complex::new(1.0, 0.0), |acc, j| acc * (roots[i] - roots[j]));
+            let step = evaluate(roots[i]) / denom;
+            roots[i] = roots[i] - step;
+            largest_step = largest_step.max(step.norm());
+        }
+        if largest_step < 1e-14 {
+            break;
+        }
+    }
+    roots
+}
+
+
+/// Transfer function of a recursive filter, using the coefficient convention of
+/// `filter::recursive` (Chapter 19 of the DSP Guide):
+///
+/// ```text
+/// y(t) = a0 x(t) + a1 x(t-1) + ... + b1 y(t-1) + b2 y(t-2) + ...
+/// ```
+///
+/// where `b_coeffs[0]` is `b1`. This corresponds to:
+///
+/// ```text
+///         a0 + a1 z^-1 + a2 z^-2 + ...
+/// H(z) = ------------------------------
+///         1 - b1 z^-1 - b2 z^-2 - ...
+/// ```
+#[derive(Debug, Clone)]
+pub struct TransferFunction {
+    a_coeffs: Vec<f64>,
+    b_coeffs: Vec<f64>,
+}
+
+
+impl TransferFunction {
+    pub fn new(a_coeffs: &[f64], b_coeffs: &[f64]) -> Self {
+        Self {
+            a_coeffs: a_coeffs.to_vec(),
+            b_coeffs: b_coeffs.to_vec(),
+        }
+    }
+
+    // denominator coefficients including the leading 1, in powers of z^-1
+    fn denominator(&self) -> Vec<f64> {
+        std::iter::once(1.0).chain(self.b_coeffs.iter().map(|b| -b)).collect()
+    }
+
+    /// Complex response at a single frequency.
+    pub fn evaluate(&self, sample_rate: u32, frequency: f64) -> Complex {
+        let z_inverse = Complex::from_angle(-2.0 * PI * frequency / sample_rate as f64);
+        let numerator = evaluate_inverse(&self.a_coeffs, z_inverse);
+        numerator / evaluate_inverse(&self.denominator(), z_inverse)
+    }
+
+    /// Magnitude and phase at each of the provided frequencies.
+    pub fn response(&self, sample_rate: u32, frequencies: &[f64]) -> FrequencyResponse {
+        let response = frequencies.iter().map(|&f| self.evaluate(sample_rate, f)).collect();
+        FrequencyResponse::from_complex(frequencies, response)
+    }
+
+    /// Locations in the z-plane where the response is zero.
+    pub fn zeros(&self) -> Vec<Complex> {
+        // multiplying through by z^N turns the series in z^-1 into a polynomial in z
+        polynomial_roots(&self.a_coeffs)
+    }
+
+    /// Locations in the z-plane where the response is infinite.
+    pub fn poles(&self) -> Vec<Complex> {
+        polynomial_roots(&self.denominator())
+    }
+
+    /// Fail if any pole lies on or outside of the unit circle, in which case the filter's output
+    /// either grows without bound or rings forever.
+    pub fn check_stability(&self) -> Result<()> {
+        let unstable: Vec<String> = self.poles()
+            .iter()
+            .filter(|p| p.norm() >= 1.0)
+            .map(|p| format!("{} (|p| = {:.6})", p, p.norm()))
+            .collect();
+        if unstable.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "unstable recursive filter (a: {:?}, b: {:?}): poles on or outside the unit \
+                circle: {}", self.a_coeffs, self.b_coeffs, unstable.join(", ")
+            ))
+        }
+    }
+}
+
+
+/// Measure the response of an arbitrary `Filter` from its impulse response.
+///
+/// The filter is fed a unit impulse followed by `length - 1` zeros, so `length` should comfortably
+/// exceed the time the filter takes to ring out. Note that this advances the filter's internal
+/// state; measure a freshly constructed `Filter` rather than one already in use.
+pub fn measure(
+    filter: &mut Filter,
+    sample_rate: u32,
+    length: usize,
+    frequencies: &[f64],
+) -> FrequencyResponse {
+    let impulse_response: Vec<f64> = (0 .. length)
+        .map(|i| filter(if i == 0 { 1.0 } else { 0.0 }) as f64)
+        .collect();
+    let response = frequencies
+        .iter()
+        .map(|&f| {
+            let z_inverse = Complex::from_angle(-2.0 * PI * f / sample_rate as f64);
+            evaluate_inverse(&impulse_response, z_inverse)
+        })
+        .collect();
+    FrequencyResponse::from_complex(frequencies, response)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter;
+
+    #[test]
+    fn test_band_pass_analysis() {
+        let (a, b) = filter::band_pass_coefficients(44100, 1000.0, 100.0);
+        let tf = TransferFunction::new(&a, &b);
+        tf.check_stability().unwrap();
+        let response = tf.response(44100, &log_frequencies(100.0, 10000.0, 401));
+        let (peak_frequency, peak_gain) = response.peak().unwrap();
+        assert!((peak_frequency - 1000.0).abs() < 20.0);
+        assert!((peak_gain - 1.0).abs() < 0.05);
+
+        // measuring the filter itself should agree with the coefficients
+        let mut bp = filter::band_pass(44100, 1000.0, 100.0);
+        let measured = measure(&mut bp, 44100, 44100, &[500.0, 1000.0, 2000.0]);
+        let exact = tf.response(44100, &[500.0, 1000.0, 2000.0]);
+        for (m, e) in measured.magnitude.iter().zip(exact.magnitude.iter()) {
+            assert!((m - e).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_poles_and_stability() {
+        // y(t) = x(t) + 0.5 y(t-1) has a single pole at 0.5
+        let stable = TransferFunction::new(&[1.0], &[0.5]);
+        let poles = stable.poles();
+        assert_eq!(poles.len(), 1);
+        assert!((poles[0] - Complex::new(0.5, 0.0)).norm() < 1e-9);
+        assert!(stable.check_stability().is_ok());
+
+        assert!(TransferFunction::new(&[1.0], &[1.5]).check_stability().is_err());
+        // a band-width wide enough to push r below -1
+        let (a, b) = filter::band_pass_coefficients(44100, 1000.0, 44100.0);
+        assert!(TransferFunction::new(&a, &b).check_stability().is_err());
+    }
+}
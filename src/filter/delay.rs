@@ -0,0 +1,241 @@
+//! Modulatable delay lines and the echo effects built on them.
+//!
+//! Unlike `filter::comb`, delays here are fractional: reads between two stored samples are
+//! interpolated, so the delay time can be swept continuously without zipper noise.
+
+use crate::{Sample, Filter, Generator, Pot};
+use crate::control::mux;
+use crate::filter;
+
+
+/// Circular buffer of past samples supporting interpolated reads at fractional delays.
+pub struct DelayLine {
+    buffer: Vec<Sample>,
+    write: usize,
+}
+
+
+impl DelayLine {
+    /// Create a `DelayLine` capable of delays of up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> Self {
+        // room for the interpolation neighbours on either side of the longest delay
+        Self {
+            buffer: vec![0.0; max_delay + 4],
+            write: 0,
+        }
+    }
+
+    /// Create a `DelayLine` capable of delays of up to `max_delay_secs` seconds.
+    pub fn from_secs(sample_rate: u32, max_delay_secs: f32) -> Self {
+        Self::new((max_delay_secs * sample_rate as f32).ceil().max(1.0) as usize)
+    }
+
+    /// Longest delay in samples that can be read back.
+    pub fn max_delay(&self) -> f32 {
+        (self.buffer.len() - 4) as f32
+    }
+
+    /// Append a new sample, overwriting the oldest one held.
+    ///
+    /// Values small enough to be denormal are flushed to zero, so lines fed back into themselves
+    /// stay cheap as their tails decay.
+    pub fn push(&mut self, sample: Sample) {
+        self.write = (self.write + 1) % self.buffer.len();
+        self.buffer[self.write] = filter::flush_denormal(sample);
+    }
+
+    // sample `n` steps back from the most recently pushed one
+    fn tap(&self, n: usize) -> Sample {
+        let len = self.buffer.len();
+        self.buffer[(self.write + len - (n % len)) % len]
+    }
+
+    /// Read the value `delay` samples back from the most recently pushed sample, where a `delay`
+    /// of zero is the most recent sample itself.
+    ///
+    /// Fractional delays are resolved with four-point cubic Hermite interpolation. Delays are
+    /// clamped to `[0, max_delay]`.
+    pub fn read(&self, delay: f32) -> Sample {
+        let delay = delay.max(0.0).min(self.max_delay());
+        let whole = delay.floor() as usize;
+        let frac = delay - delay.floor();
+
+        let newer = if whole == 0 { self.tap(0) } else { self.tap(whole - 1) };
+        let x0 = self.tap(whole);
+        let x1 = self.tap(whole + 1);
+        let older = self.tap(whole + 2);
+
+        let c1 = 0.5 * (x1 - newer);
+        let c2 = newer - 2.5 * x0 + 2.0 * x1 - 0.5 * older;
+        let c3 = 0.5 * (older - newer) + 1.5 * (x0 - x1);
+        ((c3 * frac + c2) * frac + c1) * frac + x0
+    }
+}
+
+
+/// Note length used to synchronize a delay to a tempo.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Division {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+}
+
+
+impl Division {
+    /// Length of the division counted in quarter-note beats.
+    pub fn beats(&self) -> f32 {
+        use Division::*;
+        match self {
+            Whole => 4.0,
+            Half => 2.0,
+            Quarter => 1.0,
+            Eighth => 0.5,
+            Sixteenth => 0.25,
+            ThirtySecond => 0.125,
+        }
+    }
+}
+
+
+/// Rhythmic modifier applied to a `Division`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Feel {
+    Straight,
+    Dotted,
+    Triplet,
+}
+
+
+impl Feel {
+    fn factor(&self) -> f32 {
+        match self {
+            Feel::Straight => 1.0,
+            Feel::Dotted => 1.5,
+            Feel::Triplet => 2.0 / 3.0,
+        }
+    }
+}
+
+
+/// Delay time `Pot` in seconds that follows the tempo read from `bpm`.
+///
+/// For example, a dotted eighth at 120BPM yields 0.375 seconds.
+pub fn tempo_sync<P>(bpm: P, division: Division, feel: Feel) -> impl Pot<f32>
+where
+    P: Pot<f32> + 'static,
+{
+    let beats = division.beats() * feel.factor();
+    move || 60.0 * beats / bpm.read().max(f32::EPSILON)
+}
+
+
+/// Feedback echo with interpolated, modulatable delay time.
+///
+/// - `time_secs`: delay time, clamped to `max_delay_secs`. Can be driven by `tempo_sync`.
+/// - `feedback`: portion of each echo fed back into the line, should stay on `[0, 1)`.
+/// - `tone`: low-pass filtering applied in the feedback path so that successive repeats grow
+///   darker, with the same `[0,1]` parameterization as `filter::single_pole_low_pass` (0 is
+///   unfiltered).
+/// - `mix`: wet/dry balance on `[0, 1]` where 0 is fully dry and 1 fully wet.
+pub fn echo<P1, P2, P3, P4>(
+    sample_rate: u32,
+    max_delay_secs: f32,
+    time_secs: P1,
+    feedback: P2,
+    tone: P3,
+    mix: P4,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f64> + 'static,
+    P4: Pot<f32> + 'static,
+{
+    let rate = sample_rate as f32;
+    let mut line = DelayLine::from_secs(sample_rate, max_delay_secs);
+    let mut damping = filter::single_pole_low_pass(tone);
+
+    Box::new(move |sample: Sample| {
+        // the line has not seen `sample` yet, so one sample of delay is already accounted for
+        let wet = line.read(time_secs.read() * rate - 1.0);
+        line.push(sample + feedback.read() * damping(wet));
+        let m = mix.read();
+        (1.0 - m) * sample + m * wet
+    })
+}
+
+
+/// Stereo ping-pong echo where successive repeats alternate between the left and right channels.
+///
+/// Both inputs are summed into the left delay line, whose output feeds the right line, whose
+/// output in turn feeds back into the left. Parameters are as in `echo`.
+///
+/// The yielded `Generator`s are entangled in the same way as those from `control::mux::mux2`.
+pub fn ping_pong<P1, P2, P3>(
+    sample_rate: u32,
+    max_delay_secs: f32,
+    time_secs: P1,
+    feedback: P2,
+    mix: P3,
+    left: Generator,
+    right: Generator,
+) -> (Generator, Generator)
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+{
+    let rate = sample_rate as f32;
+    let mut line_l = DelayLine::from_secs(sample_rate, max_delay_secs);
+    let mut line_r = DelayLine::from_secs(sample_rate, max_delay_secs);
+
+    let ping_pong_fun = move |l: Sample, r: Sample| {
+        let delay = time_secs.read() * rate - 1.0;
+        let fb = feedback.read();
+        let wet_l = line_l.read(delay);
+        let wet_r = line_r.read(delay);
+        line_l.push(0.5 * (l + r) + fb * wet_r);
+        line_r.push(fb * wet_l);
+        let m = mix.read();
+        ((1.0 - m) * l + m * wet_l, (1.0 - m) * r + m * wet_r)
+    };
+    mux::mux2(ping_pong_fun, left, right)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_line_read() {
+        let mut line = DelayLine::new(8);
+        for i in 0 .. 8 {
+            line.push(i as f32);
+        }
+        assert_eq!(line.read(0.0), 7.0);
+        assert_eq!(line.read(3.0), 4.0);
+        // a linear ramp should interpolate exactly
+        assert!((line.read(2.5) - 4.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_tempo_sync() {
+        let dotted_eighth = tempo_sync(120.0, Division::Eighth, Feel::Dotted);
+        assert!((dotted_eighth.read() - 0.375).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_echo_delay() {
+        let mut delay = echo(1000, 0.1, 0.01, 0.0, 0.0, 1.0);
+        assert_eq!(delay(1.0), 0.0);
+        for _ in 1 .. 10 {
+            assert_eq!(delay(0.0), 0.0);
+        }
+        assert!((delay(0.0) - 1.0).abs() < 1e-6);
+    }
+}
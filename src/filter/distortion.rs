@@ -0,0 +1,271 @@
+//! Nonlinear distortion effects.
+
+use std::f32::consts::PI;
+
+use crate::{Sample, Filter, Pot};
+
+
+/// Transfer function applied by a `waveshaper`.
+///
+/// The built-in shapes all map `0` to `0` and saturate (or fold) around `[-1, 1]`.
+pub enum Shape {
+    /// Smooth symmetric saturation.
+    Tanh,
+    /// Cubic soft clipper, linear-ish near zero and flat beyond `[-1, 1]`.
+    SoftClip,
+    /// Reflects anything beyond `[-1, 1]` back into range, rich in high harmonics.
+    Foldback,
+    /// Asymmetric saturation that clips the positive half harder, adding even harmonics like an
+    /// overdriven triode. Note that asymmetric shaping introduces some DC offset.
+    Tube,
+    /// User-supplied transfer function.
+    Custom(Box<dyn Fn(Sample) -> Sample + Send>),
+}
+
+
+impl Shape {
+    /// Transfer the provided value through this `Shape`.
+    pub fn apply(&self, x: Sample) -> Sample {
+        match self {
+            Shape::Tanh => x.tanh(),
+            Shape::SoftClip => {
+                if x >= 1.0 {
+                    1.0
+                } else if x <= -1.0 {
+                    -1.0
+                } else {
+                    1.5 * x - 0.5 * x.powi(3)
+                }
+            },
+            Shape::Foldback => {
+                let t = 0.25 * (x + 1.0);
+                4.0 * (t - (t + 0.5).floor()).abs() - 1.0
+            },
+            Shape::Tube => {
+                const BIAS: f32 = 0.3;
+                (x + BIAS).tanh() - BIAS.tanh()
+            },
+            Shape::Custom(f) => f(x),
+        }
+    }
+}
+
+
+/// Rate multiplier at which a `waveshaper` runs internally.
+///
+/// Shaping creates harmonics above the Nyquist frequency which fold back down as inharmonic
+/// aliasing. Running the shaper at a multiple of the sample rate and low-passing before returning
+/// to the original rate removes most of it, at a proportional cost in processing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Oversampling {
+    None,
+    X2,
+    X4,
+    X8,
+}
+
+
+impl Oversampling {
+    // number of 2x stages required to reach this rate
+    fn stages(&self) -> usize {
+        match self {
+            Oversampling::None => 0,
+            Oversampling::X2 => 1,
+            Oversampling::X4 => 2,
+            Oversampling::X8 => 3,
+        }
+    }
+}
+
+
+/// Number of taps of the anti-aliasing filters between oversampling stages.
+const HALF_BAND_TAPS: usize = 31;
+
+
+// windowed-sinc low-pass at a quarter of the rate (half of the lower stage's Nyquist)
+fn half_band_kernel() -> Vec<f32> {
+    let m = (HALF_BAND_TAPS - 1) as f32;
+    let kernel: Vec<f32> = (0 .. HALF_BAND_TAPS)
+        .map(|i| {
+            let n = i as f32 - m / 2.0;
+            let sinc = if n == 0.0 { 0.5 } else { (0.5 * PI * n).sin() / (PI * n) };
+            let blackman = 0.42
+                - 0.5 * (2.0 * PI * i as f32 / m).cos()
+                + 0.08 * (4.0 * PI * i as f32 / m).cos();
+            sinc * blackman
+        })
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|k| k / sum).collect()
+}
+
+
+// FIR low-pass with its own history, one per oversampling stage and direction
+struct HalfBand {
+    kernel: Vec<f32>,
+    history: Vec<f32>,
+    position: usize,
+}
+
+
+impl HalfBand {
+    fn new() -> Self {
+        Self {
+            kernel: half_band_kernel(),
+            history: vec![0.0; HALF_BAND_TAPS],
+            position: 0,
+        }
+    }
+
+    fn process(&mut self, sample: Sample) -> Sample {
+        self.position = (self.position + 1) % HALF_BAND_TAPS;
+        self.history[self.position] = sample;
+        let mut out = 0f32;
+        for (i, k) in self.kernel.iter().enumerate() {
+            out += k * self.history[(self.position + HALF_BAND_TAPS - i) % HALF_BAND_TAPS];
+        }
+        out
+    }
+}
+
+
+/// Waveshaping distortion.
+///
+/// The input is multiplied by `drive` (1 is unity, 10-50 for heavy overdrive) and passed through
+/// `shape` at the requested `oversampling` rate. The output level is not compensated, so it is
+/// commonly followed by a `gain`.
+///
+/// Oversampling filters add a latency of 15 samples per 2x stage at that stage's rate.
+pub fn waveshaper<P>(shape: Shape, drive: P, oversampling: Oversampling) -> Filter
+where
+    P: Pot<f32> + 'static,
+{
+    let n_stages = oversampling.stages();
+    let mut upsamplers: Vec<HalfBand> = (0 .. n_stages).map(|_| HalfBand::new()).collect();
+    let mut downsamplers: Vec<HalfBand> = (0 .. n_stages).map(|_| HalfBand::new()).collect();
+    let mut scratch = [[0f32; 8]; 2];
+
+    Box::new(move |sample: Sample| {
+        let [ref mut src, ref mut dst] = scratch;
+        src[0] = sample * drive.read();
+        let mut len = 1;
+
+        // zero-stuff and interpolate up to the oversampled rate
+        for stage in upsamplers.iter_mut() {
+            for i in 0 .. len {
+                // doubling compensates for the energy lost to the inserted zeros
+                dst[2 * i] = stage.process(2.0 * src[i]);
+                dst[2 * i + 1] = stage.process(0.0);
+            }
+            std::mem::swap(src, dst);
+            len *= 2;
+        }
+
+        for value in src[.. len].iter_mut() {
+            *value = shape.apply(*value);
+        }
+
+        // band-limit and decimate back down to the original rate
+        for stage in downsamplers.iter_mut().rev() {
+            len /= 2;
+            for i in 0 .. len {
+                stage.process(src[2 * i]);
+                dst[i] = stage.process(src[2 * i + 1]);
+            }
+            std::mem::swap(src, dst);
+        }
+        src[0]
+    })
+}
+
+
+/// Lo-fi bit depth and sample rate reduction.
+///
+/// - `bits`: resolution that samples on `[-1, 1]` are quantized to. Fractional values are allowed
+///   for a continuous sweep, e.g. 8 for classic 8-bit and 12 for sampler-era grit.
+/// - `downsample`: each quantized value is held for this many samples (at least 1), reducing the
+///   effective sample rate. Fractional factors are allowed and produce the characteristic
+///   inharmonic aliasing.
+/// - `dither`: add triangular noise of one quantization step before quantizing, trading the
+///   harsh distortion of low bit depths for a noise floor.
+pub fn bitcrush<P1, P2>(bits: P1, downsample: P2, dither: bool) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+{
+    let mut phase = 0f32;
+    let mut held = 0f32;
+
+    Box::new(move |sample: Sample| {
+        if phase <= 0.0 {
+            phase += downsample.read().max(1.0);
+            let step = 2f32.powf(1.0 - bits.read().max(1.0));
+            let noise = if dither {
+                (rand::random::<f32>() - rand::random::<f32>()) * step
+            } else {
+                0.0
+            };
+            held = ((sample + noise) / step).round() * step;
+        }
+        phase -= 1.0;
+        held
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shapes() {
+        for shape in &[Shape::Tanh, Shape::SoftClip, Shape::Foldback, Shape::Tube] {
+            assert!(shape.apply(0.0).abs() < 1e-6);
+        }
+        assert_eq!(Shape::SoftClip.apply(3.0), 1.0);
+        assert!((Shape::Foldback.apply(1.5) - 0.5).abs() < 1e-6);
+        assert_eq!(Shape::Custom(Box::new(|x| x * 2.0)).apply(0.25), 0.5);
+    }
+
+    #[test]
+    fn test_oversampled_unity() {
+        // an identity shape should pass DC through every oversampling configuration unchanged
+        for oversampling in &[Oversampling::None, Oversampling::X2, Oversampling::X8] {
+            let mut shaper = waveshaper(Shape::Custom(Box::new(|x| x)), 1.0, *oversampling);
+            let settled = (0 .. 200).map(|_| shaper(0.5)).last().unwrap();
+            assert!((settled - 0.5).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_bitcrush_levels() {
+        // 3 bits split [-1, 1) into 8 levels, a quarter apart
+        let mut crusher = bitcrush(3.0, 1.0, false);
+        let mut levels: Vec<Sample> = (0 .. 1000)
+            .map(|i| crusher(-1.0 + 1.875 * i as Sample / 1000.0))
+            .collect();
+        levels.dedup();
+        assert_eq!(levels, vec![-1.0, -0.75, -0.5, -0.25, 0.0, 0.25, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_bitcrush_downsample() {
+        let mut crusher = bitcrush(24.0, 4.0, false);
+        let held: Vec<Sample> = (1 .. 10).map(|i| crusher(i as Sample / 16.0)).collect();
+        let expected: Vec<Sample> = [1, 1, 1, 1, 5, 5, 5, 5, 9]
+            .iter()
+            .map(|&i| i as Sample / 16.0)
+            .collect();
+        assert_eq!(held, expected);
+    }
+
+    #[test]
+    fn test_bitcrush_without_dither_is_deterministic() {
+        let input: Vec<Sample> = (0 .. 500).map(|i| (i as Sample * 0.01).sin()).collect();
+        let run = || {
+            let mut crusher = bitcrush(5.5, 2.5, false);
+            input.iter().map(|&sample| crusher(sample)).collect::<Vec<Sample>>()
+        };
+        assert_eq!(run(), run());
+    }
+}
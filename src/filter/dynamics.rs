@@ -0,0 +1,383 @@
+//! Dynamics processors: compressors, limiters, expanders and gates.
+//!
+//! All processors here detect level either from the stream they are applied to or, when one is
+//! provided, from a sidechain `Generator`. A sidechain is called exactly once for every sample the
+//! `Filter` processes, so it should be driven at the same rate as the filtered stream (e.g. one
+//! side of `control::flow::fork`).
+
+use std::collections::VecDeque;
+
+use crate::{Sample, Filter, Generator, Pot};
+use crate::filter::flush_denormal;
+
+
+/// Level floor used when converting silence to decibels.
+const SILENCE_DB: f32 = -120.0;
+
+
+/// Convert a level in decibels to a linear gain factor.
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+
+/// Convert a linear gain factor to decibels, flooring silence at -120dB.
+pub fn gain_to_db(gain: f32) -> f32 {
+    let magnitude = gain.abs();
+    if magnitude <= 0.0 {
+        SILENCE_DB
+    } else {
+        (20.0 * magnitude.log10()).max(SILENCE_DB)
+    }
+}
+
+
+/// Attack and release times of a level detector.
+///
+/// The attack time governs how quickly the detector responds to a rising level, the release time
+/// how quickly it lets go once the level falls again. Both are time constants in seconds: the time
+/// taken to cover ~63% of a step change.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ballistics {
+    pub attack_secs: f32,
+    pub release_secs: f32,
+}
+
+
+impl Ballistics {
+    pub fn new(attack_secs: f32, release_secs: f32) -> Self {
+        Self { attack_secs, release_secs }
+    }
+}
+
+
+// coefficient of a one-pole smoother with the provided time constant, zero meaning "instant"
+pub(crate) fn time_coefficient(sample_rate: u32, secs: f32) -> f32 {
+    if secs <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (secs * sample_rate as f32)).exp()
+    }
+}
+
+
+/// One-pole envelope tracker with independent attack and release coefficients.
+pub(crate) struct Envelope {
+    attack: f32,
+    release: f32,
+    level: f32,
+}
+
+
+impl Envelope {
+    pub(crate) fn new(sample_rate: u32, ballistics: Ballistics) -> Self {
+        Self {
+            attack: time_coefficient(sample_rate, ballistics.attack_secs),
+            release: time_coefficient(sample_rate, ballistics.release_secs),
+            level: 0.0,
+        }
+    }
+
+    /// Follow the provided (already rectified) input, returning the current envelope level.
+    pub(crate) fn track(&mut self, input: f32) -> f32 {
+        let coeff = if input > self.level { self.attack } else { self.release };
+        self.level = flush_denormal(input + coeff * (self.level - input));
+        self.level
+    }
+}
+
+
+// static curve of a downward compressor: change in level (dB, <= 0) for an input level
+fn compression_db(level_db: f32, threshold_db: f32, ratio: f32, knee_db: f32) -> f32 {
+    let over = level_db - threshold_db;
+    if 2.0 * over < -knee_db {
+        0.0
+    } else if knee_db > 0.0 && 2.0 * over.abs() <= knee_db {
+        (1.0 / ratio - 1.0) * (over + knee_db / 2.0).powi(2) / (2.0 * knee_db)
+    } else {
+        over * (1.0 / ratio - 1.0)
+    }
+}
+
+
+// static curve of a downward expander: change in level (dB, <= 0) for an input level
+fn expansion_db(level_db: f32, threshold_db: f32, ratio: f32, knee_db: f32) -> f32 {
+    let over = level_db - threshold_db;
+    if 2.0 * over > knee_db {
+        0.0
+    } else if knee_db > 0.0 && 2.0 * over.abs() <= knee_db {
+        - (ratio - 1.0) * (over - knee_db / 2.0).powi(2) / (2.0 * knee_db)
+    } else {
+        over * (ratio - 1.0)
+    }
+}
+
+
+/// Feed-forward compressor.
+///
+/// Levels above `threshold_db` are reduced by `ratio` (e.g. a `ratio` of 4 turns 8dB over the
+/// threshold into 2dB over), with the transition softened across a knee `knee_db` wide. The gain
+/// reduction follows the provided `ballistics` and `makeup_db` of gain is applied afterwards.
+///
+/// When a `sidechain` is provided, its level drives the gain reduction instead of the input's.
+pub fn compressor<P1, P2, P3>(
+    sample_rate: u32,
+    threshold_db: P1,
+    ratio: P2,
+    knee_db: f32,
+    ballistics: Ballistics,
+    makeup_db: P3,
+    mut sidechain: Option<Generator>,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+{
+    let mut reduction = Envelope::new(sample_rate, ballistics);
+    Box::new(move |sample: Sample| {
+        let detected = match sidechain {
+            Some(ref mut sc) => sc(),
+            None => sample,
+        };
+        let target = - compression_db(
+            gain_to_db(detected),
+            threshold_db.read(),
+            ratio.read().max(1.0),
+            knee_db,
+        );
+        let reduction_db = reduction.track(target);
+        sample * db_to_gain(makeup_db.read() - reduction_db)
+    })
+}
+
+
+/// Downward expander, the mirror image of the `compressor`.
+///
+/// Levels below `threshold_db` are pushed further down by `ratio` (e.g. a `ratio` of 2 turns 5dB
+/// under the threshold into 10dB under) with a knee `knee_db` wide.
+pub fn expander<P1, P2>(
+    sample_rate: u32,
+    threshold_db: P1,
+    ratio: P2,
+    knee_db: f32,
+    ballistics: Ballistics,
+    mut sidechain: Option<Generator>,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+{
+    let mut detector = Envelope::new(sample_rate, Ballistics::new(0.0, ballistics.release_secs));
+    let mut reduction = Envelope::new(sample_rate, ballistics);
+    Box::new(move |sample: Sample| {
+        let detected = match sidechain {
+            Some(ref mut sc) => sc(),
+            None => sample,
+        };
+        let level = detector.track(detected.abs());
+        let target = - expansion_db(
+            gain_to_db(level),
+            threshold_db.read(),
+            ratio.read().max(1.0),
+            knee_db,
+        );
+        // `SILENCE_DB` bounds the attenuation so the envelope can recover in reasonable time
+        let reduction_db = reduction.track(target.min(-SILENCE_DB));
+        sample * db_to_gain(-reduction_db)
+    })
+}
+
+
+/// Noise gate.
+///
+/// The gate opens as soon as the detected level rises above `threshold_db` and closes once it has
+/// spent `hold_secs` below it. Opening follows the attack and closing the release of the provided
+/// `ballistics`. When closed, the stream is attenuated by `range_db` (e.g. 80 for a hard gate,
+/// something like 12 for gentle background reduction).
+pub fn gate<P>(
+    sample_rate: u32,
+    threshold_db: P,
+    hold_secs: f32,
+    ballistics: Ballistics,
+    range_db: f32,
+    mut sidechain: Option<Generator>,
+) -> Filter
+where
+    P: Pot<f32> + 'static,
+{
+    let hold_steps = (hold_secs * sample_rate as f32) as u64;
+    let floor = db_to_gain(-range_db.abs());
+    // peak detector with an instant attack so that transients open the gate immediately
+    let mut detector = Envelope::new(sample_rate, Ballistics::new(0.0, 0.01));
+    let mut gain = Envelope::new(sample_rate, ballistics);
+    let mut n_below = hold_steps + 1;
+
+    Box::new(move |sample: Sample| {
+        let detected = match sidechain {
+            Some(ref mut sc) => sc(),
+            None => sample,
+        };
+        let level_db = gain_to_db(detector.track(detected.abs()));
+        if level_db >= threshold_db.read() {
+            n_below = 0;
+        } else if n_below <= hold_steps {
+            n_below += 1;
+        }
+        let target = if n_below <= hold_steps { 1.0 } else { floor };
+        sample * gain.track(target)
+    })
+}
+
+
+// sliding-window minimum over the most recent `window` values
+struct MovingMin {
+    window: usize,
+    index: usize,
+    candidates: VecDeque<(usize, f32)>,
+}
+
+
+impl MovingMin {
+    fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            index: 0,
+            candidates: VecDeque::with_capacity(window.max(1) + 1),
+        }
+    }
+
+    fn push(&mut self, value: f32) -> f32 {
+        while let Some(&(_, back)) = self.candidates.back() {
+            if back < value {
+                break;
+            }
+            self.candidates.pop_back();
+        }
+        self.candidates.push_back((self.index, value));
+        while let Some(&(i, _)) = self.candidates.front() {
+            if i + self.window > self.index {
+                break;
+            }
+            self.candidates.pop_front();
+        }
+        self.index += 1;
+        self.candidates.front().map(|&(_, v)| v).unwrap_or(value)
+    }
+}
+
+
+/// Brickwall lookahead limiter.
+///
+/// The stream is delayed by `lookahead_secs` so that gain reduction can be ramped in over that
+/// window ahead of every peak, guaranteeing that the output never exceeds `ceiling_db` without
+/// the distortion of hard clipping. Gain then recovers over `release_secs`.
+///
+/// With a `sidechain` the gain reduction is driven by the sidechain level instead and the ceiling
+/// is no longer guaranteed for the filtered stream itself.
+pub fn limiter(
+    sample_rate: u32,
+    ceiling_db: f32,
+    lookahead_secs: f32,
+    release_secs: f32,
+    mut sidechain: Option<Generator>,
+) -> Filter {
+    let ceiling = db_to_gain(ceiling_db);
+    let lookahead = (lookahead_secs * sample_rate as f32).max(0.0) as usize;
+    let release = time_coefficient(sample_rate, release_secs);
+
+    let mut delayed: VecDeque<Sample> = VecDeque::from(vec![0.0; lookahead]);
+    // the minimum over (lookahead + 1) required gains covers every sample still in the delay line,
+    // and averaging (lookahead + 1) of those minimums ramps into each peak without overshooting
+    let mut minimum = MovingMin::new(lookahead + 1);
+    let mut averaged: VecDeque<f32> = VecDeque::from(vec![1.0; lookahead + 1]);
+    let mut average_sum = (lookahead + 1) as f64;
+    let mut gain = 1f32;
+
+    Box::new(move |sample: Sample| {
+        let detected = match sidechain {
+            Some(ref mut sc) => sc(),
+            None => sample,
+        };
+        let required = if detected.abs() > ceiling { ceiling / detected.abs() } else { 1.0 };
+
+        let held = minimum.push(required);
+        averaged.push_back(held);
+        average_sum += held as f64 - averaged.pop_front().unwrap_or(1.0) as f64;
+        let target = (average_sum / (lookahead + 1) as f64) as f32;
+
+        gain = if target < gain { target } else { target + release * (gain - target) };
+
+        delayed.push_back(sample);
+        let out = delayed.pop_front().unwrap_or(0.0) * gain;
+        // guard against accumulated rounding in the running average
+        out.max(-ceiling).min(ceiling)
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    #[test]
+    fn test_db_conversion() {
+        assert!((db_to_gain(-6.0206) - 0.5).abs() < 1e-4);
+        assert!((gain_to_db(0.1) + 20.0).abs() < 1e-4);
+        assert_eq!(gain_to_db(0.0), SILENCE_DB);
+    }
+
+    #[test]
+    fn test_static_curves() {
+        // hard knee: 8dB over at 4:1 comes out 2dB over
+        assert!((compression_db(-2.0, -10.0, 4.0, 0.0) + 6.0).abs() < 1e-4);
+        assert_eq!(compression_db(-20.0, -10.0, 4.0, 6.0), 0.0);
+        // hard knee: 5dB under at 2:1 comes out 10dB under
+        assert!((expansion_db(-15.0, -10.0, 2.0, 0.0) + 5.0).abs() < 1e-4);
+        assert_eq!(expansion_db(0.0, -10.0, 2.0, 6.0), 0.0);
+    }
+
+    #[test]
+    fn test_hard_knee_at_threshold() {
+        assert_eq!(compression_db(0.0, 0.0, 4.0, 0.0), 0.0);
+        assert_eq!(expansion_db(0.0, 0.0, 2.0, 0.0), 0.0);
+
+        // a full-scale square wave sits exactly at a 0dB threshold
+        let mut compress = compressor(
+            44100, 0.0, 4.0, 0.0, Ballistics::new(0.001, 0.05), 0.0, None,
+        );
+        let mut sign = 1.0;
+        for _ in 0 .. 1000 {
+            sign = -sign;
+            assert!(compress(sign).is_finite());
+        }
+    }
+
+    #[test]
+    fn test_limiter_ceiling() {
+        let rate = 44100;
+        let ceiling_db = -6.0;
+        let mut source = generator::sine(rate, 220.0);
+        let mut limit = limiter(rate, ceiling_db, 0.005, 0.05, None);
+        for _ in 0 .. rate {
+            assert!(limit(source() * 4.0).abs() <= db_to_gain(ceiling_db) + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_compressor_reduces_level() {
+        let rate = 44100;
+        let mut source = generator::sine(rate, 220.0);
+        let mut compress = compressor(
+            rate, -20.0, 4.0, 0.0, Ballistics::new(0.001, 0.05), 0.0, None,
+        );
+        let peak = (0 .. rate)
+            .map(|_| compress(source()))
+            .skip(rate as usize / 2)
+            .fold(0f32, |acc, s| acc.max(s.abs()));
+        // 20dB over at 4:1 should settle near 15dB of reduction
+        assert!(gain_to_db(peak) < -10.0);
+        assert!(gain_to_db(peak) > -20.0);
+    }
+}
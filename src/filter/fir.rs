@@ -0,0 +1,360 @@
+//! Windowed-sinc FIR filters.
+//!
+//! Implements the filter design of Chapters 14-16 of
+//! [_The Scientist and Engineer's Guide to Digital Signal Processing_](https://www.analog.com/media/en/technical-documentation/dsp-book/dsp_book_Ch16.pdf):
+//! an ideal (sinc) low-pass kernel is truncated, shaped by a window and combined by spectral
+//! inversion into high-pass, band-pass and band-reject kernels. The kernels are symmetric, so
+//! every filter here is linear-phase: all frequencies are delayed by the same
+//! `(kernel.len() - 1) / 2` samples, which keeps crossovers and resamplers phase-coherent.
+
+use std::f64::consts::PI;
+
+use anyhow::{anyhow, Result};
+
+use crate::{Sample, Filter};
+use crate::filter::analysis::Complex;
+
+
+/// Kernels longer than this are convolved through the FFT rather than directly.
+const FFT_THRESHOLD: usize = 64;
+
+
+/// Longest kernel `design` will build, ~24s at 44.1kHz.
+pub const MAX_KERNEL_LENGTH: usize = 1 << 20;
+
+
+/// Window applied to the truncated sinc.
+///
+/// The window trades the width of the transition band for stopband attenuation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Window {
+    /// ~53dB of stopband attenuation with a fairly narrow transition.
+    Hamming,
+    /// ~74dB of stopband attenuation, at the cost of a transition ~20% wider than `Hamming`.
+    Blackman,
+    /// Kaiser window tuned to reach the requested stopband attenuation (dB) with the shortest
+    /// kernel.
+    Kaiser { attenuation_db: f64 },
+}
+
+
+impl Window {
+    /// Number of taps needed for a transition band `transition` wide, as a fraction of the sample
+    /// rate. Always odd, so that the kernel has a center tap.
+    fn length(&self, transition: f64) -> usize {
+        let length = match self {
+            Window::Hamming => 3.3 / transition,
+            Window::Blackman => 4.0 / transition,
+            Window::Kaiser { attenuation_db } => {
+                (attenuation_db - 7.95).max(0.0) / (14.36 * transition) + 1.0
+            },
+        };
+        (length.ceil() as usize).max(3) | 1
+    }
+
+    // weight of tap `n` of a window `length` taps long
+    fn weight(&self, n: usize, length: usize) -> f64 {
+        let m = (length - 1) as f64;
+        let n = n as f64;
+        match self {
+            Window::Hamming => 0.54 - 0.46 * (2.0 * PI * n / m).cos(),
+            Window::Blackman => {
+                0.42 - 0.5 * (2.0 * PI * n / m).cos() + 0.08 * (4.0 * PI * n / m).cos()
+            },
+            Window::Kaiser { attenuation_db } => {
+                let beta = kaiser_beta(*attenuation_db);
+                let x = 2.0 * n / m - 1.0;
+                bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(beta)
+            },
+        }
+    }
+}
+
+
+// Kaiser's empirical shape parameter for the provided stopband attenuation
+fn kaiser_beta(attenuation_db: f64) -> f64 {
+    if attenuation_db > 50.0 {
+        0.1102 * (attenuation_db - 8.7)
+    } else if attenuation_db >= 21.0 {
+        0.5842 * (attenuation_db - 21.0).powf(0.4) + 0.07886 * (attenuation_db - 21.0)
+    } else {
+        0.0
+    }
+}
+
+
+// zeroth-order modified Bessel function of the first kind, by its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > 1e-12 * sum {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+
+/// Frequency response of a designed filter, with all frequencies in Hz.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Response {
+    LowPass { cutoff: f64 },
+    HighPass { cutoff: f64 },
+    BandPass { low: f64, high: f64 },
+    BandReject { low: f64, high: f64 },
+}
+
+
+// windowed-sinc low-pass kernel with unity gain at DC, `cutoff` as a fraction of the sample rate
+fn low_pass_kernel(cutoff: f64, length: usize, window: Window) -> Vec<f64> {
+    let center = (length / 2) as f64;
+    let kernel: Vec<f64> = (0 .. length)
+        .map(|n| {
+            let offset = n as f64 - center;
+            let sinc = if offset == 0.0 {
+                2.0 * PI * cutoff
+            } else {
+                (2.0 * PI * cutoff * offset).sin() / offset
+            };
+            sinc * window.weight(n, length)
+        })
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+
+// flip the response of a kernel upside down (Chapter 14): pass what was stopped and vice versa
+fn spectral_inversion(mut kernel: Vec<f64>) -> Vec<f64> {
+    for k in kernel.iter_mut() {
+        *k = -*k;
+    }
+    let center = kernel.len() / 2;
+    kernel[center] += 1.0;
+    kernel
+}
+
+
+/// Design a windowed-sinc kernel with the provided `response`.
+///
+/// `transition_hz` is the width of the band over which the response rolls from pass to stop; the
+/// kernel length is chosen to meet it with the provided `window`, so halving the transition width
+/// doubles the length (and the cost of running the filter).
+///
+/// Fails unless the transition is a positive width narrow enough to need no more than
+/// `MAX_KERNEL_LENGTH` taps.
+pub fn design(
+    sample_rate: u32,
+    response: Response,
+    transition_hz: f64,
+    window: Window,
+) -> Result<Vec<f64>> {
+    let rate = sample_rate as f64;
+    let transition = transition_hz / rate;
+    if !(transition > 0.0 && transition.is_finite()) {
+        return Err(anyhow!(
+            "invalid transition width {}Hz at a sample rate of {}Hz", transition_hz, sample_rate
+        ));
+    }
+    let length = window.length(transition);
+    if length > MAX_KERNEL_LENGTH {
+        return Err(anyhow!(
+            "a {}Hz transition needs {} taps, more than the maximum of {}",
+            transition_hz, length, MAX_KERNEL_LENGTH
+        ));
+    }
+    let low_pass = |cutoff: f64| low_pass_kernel(cutoff / rate, length, window);
+    let kernel = match response {
+        Response::LowPass { cutoff } => low_pass(cutoff),
+        Response::HighPass { cutoff } => spectral_inversion(low_pass(cutoff)),
+        Response::BandPass { low, high } => {
+            // a low-pass minus a lower low-pass leaves the band in between
+            let below = low_pass(low);
+            low_pass(high).iter().zip(below.iter()).map(|(h, l)| h - l).collect()
+        },
+        Response::BandReject { low, high } => {
+            let below = spectral_inversion(low_pass(high));
+            low_pass(low).iter().zip(below.iter()).map(|(l, h)| l + h).collect()
+        },
+    };
+    Ok(kernel)
+}
+
+
+/// Design a windowed-sinc filter with the provided `response` and run it with `convolve`.
+///
+/// Fails on the same transition widths as `design`.
+pub fn windowed_sinc(
+    sample_rate: u32,
+    response: Response,
+    transition_hz: f64,
+    window: Window,
+) -> Result<Filter> {
+    Ok(convolve(design(sample_rate, response, transition_hz, window)?))
+}
+
+
+/// Convolve the stream with the provided kernel.
+///
+/// Kernels of up to 64 taps are convolved directly. Longer ones use FFT convolution
+/// (overlap-save), which costs O(log n) per sample rather than O(n) but processes the stream in
+/// blocks and so adds a latency of `kernel.len().next_power_of_two()` samples on top of the
+/// kernel's own delay.
+pub fn convolve(kernel: Vec<f64>) -> Filter {
+    if kernel.len() <= FFT_THRESHOLD {
+        direct(kernel)
+    } else {
+        overlap_save(kernel)
+    }
+}
+
+
+// direct-form convolution over a circular buffer of past inputs
+fn direct(kernel: Vec<f64>) -> Filter {
+    let length = kernel.len().max(1);
+    let mut history = vec![0f64; length];
+    let mut position = 0;
+    Box::new(move |sample: Sample| {
+        position = (position + 1) % length;
+        history[position] = sample as f64;
+        let out = kernel
+            .iter()
+            .enumerate()
+            .fold(0.0, |acc, (i, k)| acc + k * history[(position + length - i) % length]);
+        out as Sample
+    })
+}
+
+
+// in-place iterative radix-2 FFT; the length of `buffer` must be a power of two
+fn fft(buffer: &mut [Complex], inverse: bool) {
+    let n = buffer.len();
+    let bits = n.trailing_zeros();
+    for i in 0 .. n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut size = 2;
+    while size <= n {
+        let step = Complex::from_angle(sign * 2.0 * PI / size as f64);
+        for start in (0 .. n).step_by(size) {
+            let mut twiddle = Complex::new(1.0, 0.0);
+            for k in 0 .. size / 2 {
+                let even = buffer[start + k];
+                let odd = buffer[start + k + size / 2] * twiddle;
+                buffer[start + k] = even + odd;
+                buffer[start + k + size / 2] = even - odd;
+                twiddle = twiddle * step;
+            }
+        }
+        size *= 2;
+    }
+
+    if inverse {
+        for value in buffer.iter_mut() {
+            *value = value.scale(1.0 / n as f64);
+        }
+    }
+}
+
+
+// FFT convolution: each block of new inputs is transformed along with the block before it, and
+// the half of the circular convolution free of wrap-around is kept as output
+fn overlap_save(kernel: Vec<f64>) -> Filter {
+    let block = kernel.len().next_power_of_two();
+    let size = 2 * block;
+
+    let mut spectrum: Vec<Complex> = kernel.iter().map(|&k| Complex::new(k, 0.0)).collect();
+    spectrum.resize(size, Complex::default());
+    fft(&mut spectrum, false);
+
+    let mut inputs = vec![0f64; size];
+    let mut outputs = vec![0f64; block];
+    let mut scratch = vec![Complex::default(); size];
+    let mut position = 0;
+
+    Box::new(move |sample: Sample| {
+        let out = outputs[position];
+        inputs[block + position] = sample as f64;
+        position += 1;
+
+        if position == block {
+            for (s, &x) in scratch.iter_mut().zip(inputs.iter()) {
+                *s = Complex::new(x, 0.0);
+            }
+            fft(&mut scratch, false);
+            for (s, &h) in scratch.iter_mut().zip(spectrum.iter()) {
+                *s = *s * h;
+            }
+            fft(&mut scratch, true);
+            for (o, s) in outputs.iter_mut().zip(scratch[block ..].iter()) {
+                *o = s.re;
+            }
+            inputs.copy_within(block .., 0);
+            position = 0;
+        }
+        out as Sample
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::analysis;
+
+    #[test]
+    fn test_design_responses() {
+        let low_pass = Response::LowPass { cutoff: 1000.0 };
+        let low_pass = design(44100, low_pass, 200.0, Window::Blackman).unwrap();
+        assert_eq!(low_pass.len() % 2, 1);
+        // linear phase: the kernel is symmetric
+        for (a, b) in low_pass.iter().zip(low_pass.iter().rev()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+
+        let tf = analysis::TransferFunction::new(&low_pass, &[]);
+        let response = tf.response(44100, &[100.0, 1500.0]);
+        assert!((response.magnitude[0] - 1.0).abs() < 0.01);
+        assert!(response.magnitude_db()[1] < -70.0);
+
+        let band_reject = Response::BandReject { low: 500.0, high: 1500.0 };
+        let kaiser = Window::Kaiser { attenuation_db: 60.0 };
+        let band_reject = design(44100, band_reject, 200.0, kaiser).unwrap();
+        let tf = analysis::TransferFunction::new(&band_reject, &[]);
+        let response = tf.response(44100, &[100.0, 1000.0, 5000.0]);
+        assert!((response.magnitude[0] - 1.0).abs() < 0.01);
+        assert!(response.magnitude_db()[1] < -50.0);
+        assert!((response.magnitude[2] - 1.0).abs() < 0.01);
+
+        for transition_hz in [0.0, -100.0, f64::NAN, f64::INFINITY, 1e-9].iter() {
+            let low_pass = Response::LowPass { cutoff: 1000.0 };
+            assert!(design(44100, low_pass, *transition_hz, Window::Hamming).is_err());
+        }
+    }
+
+    #[test]
+    fn test_fft_convolution_matches_direct() {
+        let high_pass = Response::HighPass { cutoff: 2000.0 };
+        let kernel = design(44100, high_pass, 500.0, Window::Hamming).unwrap();
+        assert!(kernel.len() > FFT_THRESHOLD);
+        let latency = kernel.len().next_power_of_two();
+        let mut slow = direct(kernel.clone());
+        let mut fast = convolve(kernel);
+
+        let input: Vec<Sample> = (0 .. 4096)
+            .map(|i| ((i * 7919) % 101) as f32 / 50.0 - 1.0)
+            .collect();
+        let expected: Vec<Sample> = input.iter().map(|&x| slow(x)).collect();
+        let actual: Vec<Sample> = input.iter().map(|&x| fast(x)).collect();
+        for (e, a) in expected.iter().zip(actual[latency ..].iter()) {
+            assert!((e - a).abs() < 1e-4);
+        }
+    }
+}
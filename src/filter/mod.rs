@@ -2,9 +2,40 @@
 
 use std::collections::VecDeque;
 use std::f32::consts::PI;
-use std::sync::{Arc, Mutex};
 
 use crate::{Sample, Filter, Generator, Pot};
+use crate::control::smooth::OnePole;
+
+pub mod dynamics;
+pub mod delay;
+pub mod modulation;
+pub mod distortion;
+pub mod resonant;
+pub mod pitch;
+pub mod vocoder;
+pub mod analysis;
+pub mod fir;
+
+pub use modulation::{chorus, flanger, phaser, first_order_all_pass, frequency_shift};
+pub use distortion::{waveshaper, bitcrush};
+pub use resonant::{svf, ladder, auto_wah};
+pub use pitch::pitch_shift;
+pub use vocoder::vocoder;
+pub use fir::windowed_sinc;
+
+
+/// Magnitude below which the state held in feedback loops is flushed to zero.
+///
+/// A decaying feedback tail otherwise shrinks into denormal floats, which many CPUs (notably ARM
+/// cores) process orders of magnitude more slowly than normal ones. At -300dB this is far below
+/// anything audible.
+const DENORMAL_THRESHOLD: f32 = 1e-15;
+
+
+// flush values too small to be heard to zero, keeping feedback state out of denormal range
+pub(crate) fn flush_denormal(value: f32) -> f32 {
+    if value.abs() < DENORMAL_THRESHOLD { 0.0 } else { value }
+}
 
 
 /// Apply the given `Filter` to the given `Generator` and return a `Generator` interface.
@@ -35,9 +66,18 @@ pub fn warble(sample_rate: u32, period: f32) -> Filter {
 }
 
 
+/// Time constant, in seconds, of the smoothing `band_pass` and `notch` (and their
+/// `AudioContext` equivalents) apply to their settings. Other filters read their `Pot`s as-is;
+/// pass it to `control::smooth::OnePole::from_secs` to smooth those the same way.
+pub const DEFAULT_SMOOTHING_SECS: f32 = 0.005;
+
+
 /// Scale the signal by the provided scale factor.
 ///
-/// No clipping is performed.
+/// No clipping is performed. The scale factor is read every sample as-is, so an audio-rate `Pot`
+/// (e.g. a `GeneratorPot`) modulates the amplitude. Wrap a stepped control in
+/// `control::smooth::OnePole` to avoid zipper noise, e.g.
+/// `OnePole::from_secs(sample_rate, DEFAULT_SMOOTHING_SECS, pot)`.
 pub fn gain<P>(scale_factor: P) -> Filter
 where
     P: Pot<f32> + 'static,
@@ -140,7 +180,7 @@ pub fn comb(
                 sample + decay_factor * buf.pop_front().unwrap_or(0.0)
             },
             CombDirection::FeedBack => {
-                let out = sample + decay_factor * buf.pop_front().unwrap_or(0.0);
+                let out = flush_denormal(sample + decay_factor * buf.pop_front().unwrap_or(0.0));
                 buf.push_back(out);
                 out
             },
@@ -174,9 +214,13 @@ pub fn parallel(mut filters: Vec<Filter>) -> Filter {
 }
 
 
-// TODO: not hardcode values, actually used provided params
+/// Schroeder reverb: four parallel feedback combs followed by two all-pass filters.
+///
+/// Any DC offset in the input is blocked on the way out, since the combs would otherwise
+/// accumulate it into the tail.
 pub fn reverb(
     sample_rate: u32,
+    // TODO: not hardcode values, actually used provided params
     _delay_secs: f32,
     _decay_factor: f32,
 ) -> Filter {
@@ -188,7 +232,8 @@ pub fn reverb(
     ]);
     let mut all_pass_a = all_pass(sample_rate, 0.02189, 0.7);
     let mut all_pass_b = all_pass(sample_rate, 0.00702, 0.7);
-    Box::new(move |sample: Sample| all_pass_b(all_pass_a(combs(sample))))
+    let mut blocker = dc_block(sample_rate, DC_BLOCK_HZ);
+    Box::new(move |sample: Sample| blocker(all_pass_b(all_pass_a(combs(sample)))))
 }
 
 
@@ -222,7 +267,7 @@ pub fn recursive(
 
 
 // less difficult to work with than `recursive` for dynamic pots, but serves the same function
-fn recursive_helper(
+pub(crate) fn recursive_helper(
     a_coeffs_len: usize,
     b_coeffs_len: usize,
 ) -> impl FnMut(Sample, &[f64], &[f64]) -> Sample
@@ -237,6 +282,7 @@ fn recursive_helper(
             let b_sum = outputs.iter().zip(b_coeffs.iter()).fold(0.0, |acc, (o, b)| acc + (o * b));
             a_sum + b_sum
         };
+        let output = if output.abs() < DENORMAL_THRESHOLD as f64 { 0.0 } else { output };
         outputs.pop_back();
         outputs.push_front(output);
         output as f32
@@ -278,6 +324,28 @@ where
 }
 
 
+/// Default corner frequency of `dc_block`, low enough to leave the audible bass untouched.
+pub const DC_BLOCK_HZ: f32 = 10.0;
+
+
+/// Remove any DC offset from the stream.
+///
+/// A single-pole high-pass with its corner at `cutoff_hz` (`DC_BLOCK_HZ` is a good default) and
+/// unity gain everywhere well above it:
+///
+/// ```text
+/// y(t) = x(t) - x(t-1) + R y(t-1),  R = 1 - 2π cutoff / sample_rate
+/// ```
+///
+/// Place it after anything that can drift or accumulate an offset: feedback loops, asymmetric
+/// waveshaping, rectification.
+pub fn dc_block(sample_rate: u32, cutoff_hz: f32) -> Filter {
+    let r = (1.0 - 2.0 * std::f64::consts::PI * cutoff_hz as f64 / sample_rate as f64).max(0.0);
+    let mut recurse = recursive_helper(2, 1);
+    Box::new(move |sample: Sample| recurse(sample, &[1.0, -1.0], &[r]))
+}
+
+
 /// Four single-pole low-pass filters stacked in series to achieve a more ideal low-pass effect.
 ///
 /// Equation 19-6 in the book. The value for`x` should be on `[0,1]`.
@@ -285,9 +353,11 @@ pub fn four_stage_low_pass<P>(x: P) -> Filter
 where
     P: Pot<f64> + 'static,
 {
-    let x_arc = Arc::new(Mutex::new(x));
-    let mut fs: Vec<Filter> = (0..4).map(|_| single_pole_low_pass(x_arc.clone())).collect();
-    Box::new(move |sample: Sample| fs.iter_mut().fold(sample, |acc, f| f(acc)))
+    let mut stages: Vec<_> = (0..4).map(|_| recursive_helper(1, 1)).collect();
+    Box::new(move |sample: Sample| {
+        let this_x = x.read();
+        stages.iter_mut().fold(sample, |acc, recurse| recurse(acc, &[1.0 - this_x], &[this_x]))
+    })
 }
 
 
@@ -304,12 +374,39 @@ fn r_and_k_from_f_and_bw(f: f64, bw: f64) -> (f64, f64) {
 }
 
 
+/// Recursion coefficients `(a, b)` of the `band_pass` filter for the provided settings.
+///
+/// Suitable for inspection with `filter::analysis::TransferFunction`.
+pub fn band_pass_coefficients(
+    sample_rate: u32,
+    center_frequency: f64,
+    band_width: f64,
+) -> ([f64; 3], [f64; 2]) {
+    let pix2 = std::f64::consts::PI * 2.0;
+    let f_frac = center_frequency / (sample_rate as f64);
+    let bw_frac = band_width / (sample_rate as f64);
+    let (r, k) = r_and_k_from_f_and_bw(f_frac, bw_frac);
+    (
+        [
+            1.0 - k,
+            2.0 * (k - r) * (pix2 * f_frac).cos(),
+            r.powi(2) - k,
+        ],
+        [
+            2.0 * r * (pix2 * f_frac).cos(),
+            - r.powi(2),
+        ],
+    )
+}
+
+
 /// Band pass filter that passes frequencies near the `center_frequency` falling off sharply
 /// outside of the `band_width`.
 ///
 /// Implementation of [Equation 19-7](https://www.analog.com/media/en/technical-documentation/dsp-book/dsp_book_Ch19.pdf).
 ///
-/// Note that values for `center_frequency` near zero cause numerical instability.
+/// Note that values for `center_frequency` near zero cause numerical instability. Changes to
+/// either setting are smoothed over a few milliseconds.
 pub fn band_pass<P1, P2>(
     sample_rate: u32,
     center_frequency: P1,
@@ -319,32 +416,52 @@ where
     P1: Pot<f64> + 'static,
     P2: Pot<f64> + 'static,
 {
+    let center_frequency =
+        OnePole::from_secs(sample_rate, DEFAULT_SMOOTHING_SECS, center_frequency);
+    let band_width = OnePole::from_secs(sample_rate, DEFAULT_SMOOTHING_SECS, band_width);
     let mut recurse = recursive_helper(3, 2);
-    let pix2 = std::f64::consts::PI * 2.0;
     Box::new(move |sample: Sample| {
-        let f_frac = center_frequency.read() / (sample_rate as f64);
-        let bw_frac = band_width.read() / (sample_rate as f64);
-        let (r, k) = r_and_k_from_f_and_bw(f_frac, bw_frac);
-        recurse(
-            sample,
-            &[
-                1.0 - k,
-                2.0 * (k - r) * (pix2 * f_frac).cos(),
-                r.powi(2) - k,
-            ],
-            &[
-                2.0 * r * (pix2 * f_frac).cos(),
-                - r.powi(2),
-            ],
-        )
+        let (a, b) = band_pass_coefficients(
+            sample_rate,
+            center_frequency.read(),
+            band_width.read(),
+        );
+        recurse(sample, &a, &b)
     })
 }
 
 
+/// Recursion coefficients `(a, b)` of the `notch` filter for the provided settings.
+///
+/// Suitable for inspection with `filter::analysis::TransferFunction`.
+pub fn notch_coefficients(
+    sample_rate: u32,
+    center_frequency: f64,
+    band_width: f64,
+) -> ([f64; 3], [f64; 2]) {
+    let pix2 = std::f64::consts::PI * 2.0;
+    let f_frac = center_frequency / (sample_rate as f64);
+    let bw_frac = band_width / (sample_rate as f64);
+    let (r, k) = r_and_k_from_f_and_bw(f_frac, bw_frac);
+    (
+        [
+            k,
+            - 2.0 * k * (pix2 * f_frac).cos(),
+            k,
+        ],
+        [
+            2.0 * r * (pix2 * f_frac).cos(),
+            - r.powi(2),
+        ],
+    )
+}
+
+
 /// The opposite of `band_pass`, `notch` passes all but those frequencies near `center_frequency`
 /// and within the `band_width`.
 ///
-/// Described by equation 19-8 in the book.
+/// Described by equation 19-8 in the book. Changes to either setting are smoothed over a few
+/// milliseconds.
 pub fn notch<P1, P2>(
     sample_rate: u32,
     center_frequency: P1,
@@ -354,24 +471,13 @@ where
     P1: Pot<f64> + 'static,
     P2: Pot<f64> + 'static,
 {
+    let center_frequency =
+        OnePole::from_secs(sample_rate, DEFAULT_SMOOTHING_SECS, center_frequency);
+    let band_width = OnePole::from_secs(sample_rate, DEFAULT_SMOOTHING_SECS, band_width);
     let mut recurse = recursive_helper(3, 2);
-    let pix2 = std::f64::consts::PI * 2.0;
     Box::new(move |sample: Sample| {
-        let f_frac = center_frequency.read() / (sample_rate as f64);
-        let bw_frac = band_width.read() / (sample_rate as f64);
-        let (r, k) = r_and_k_from_f_and_bw(f_frac, bw_frac);
-        recurse(
-            sample,
-            &[
-                k,
-                - 2.0 * k * (pix2 * f_frac).cos(),
-                k,
-            ],
-            &[
-                2.0 * r * (pix2 * f_frac).cos(),
-                - r.powi(2),
-            ],
-        )
+        let (a, b) = notch_coefficients(sample_rate, center_frequency.read(), band_width.read());
+        recurse(sample, &a, &b)
     })
 }
 
@@ -384,3 +490,35 @@ where
 {
     unimplemented!()
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gain_follows_audio_rate_pot() {
+        let mut sign = 1.0;
+        let modulator = crate::control::pot::GeneratorPot::new(Box::new(move || {
+            sign = -sign;
+            sign
+        }));
+        let mut modulate = gain(modulator);
+        assert_eq!((modulate(0.5), modulate(0.5)), (-0.5, 0.5));
+    }
+
+    #[test]
+    fn test_dc_block() {
+        let mut blocker = dc_block(44100, DC_BLOCK_HZ);
+        let settled = (0 .. 44100).map(|_| blocker(0.5)).last().unwrap();
+        assert!(settled.abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_reverb_tail_flushed() {
+        let mut filter = reverb(44100, 0.0, 0.0);
+        filter(1.0);
+        let tail = (0 .. 10 * 44100).map(|_| filter(0.0)).last().unwrap();
+        assert_eq!(tail, 0.0);
+    }
+}
@@ -0,0 +1,281 @@
+//! Classic modulation effects: chorus, flanger, phaser and frequency shifting.
+//!
+//! Each effect runs its own sinusoidal LFO at the rate read from a `Pot`. The `depth` of each
+//! effect is on `[0, 1]` and scales how much of the effect's sweep range the LFO covers.
+
+use std::f32::consts::PI;
+
+use crate::{Sample, Filter, Pot, generator};
+use crate::filter::{flush_denormal, delay::DelayLine};
+
+
+/// Shortest delay swept by the `chorus`, in seconds.
+const CHORUS_BASE_SECS: f32 = 0.015;
+
+/// Widest sweep of the `chorus` delay on top of the base delay, in seconds.
+const CHORUS_SWEEP_SECS: f32 = 0.015;
+
+/// Shortest delay swept by the `flanger`, in seconds.
+const FLANGER_BASE_SECS: f32 = 0.0005;
+
+/// Widest sweep of the `flanger` delay on top of the base delay, in seconds.
+const FLANGER_SWEEP_SECS: f32 = 0.005;
+
+/// Lowest corner frequency swept by the `phaser` all-pass stages.
+const PHASER_BASE_HZ: f32 = 200.0;
+
+/// Number of octaves above `PHASER_BASE_HZ` swept by the `phaser` at full depth.
+const PHASER_OCTAVES: f32 = 4.0;
+
+
+// LFO mapped onto [0, 1] for scaling sweep ranges
+fn unipolar_lfo<P>(sample_rate: u32, rate: P) -> impl FnMut() -> f32
+where
+    P: Pot<f32> + 'static,
+{
+    let mut lfo = generator::sine(sample_rate, rate);
+    move || 0.5 * (lfo() + 1.0)
+}
+
+
+// delay effect with an LFO-swept delay time shared by `chorus` and `flanger`
+fn swept_delay<P1, P2, P3, P4>(
+    sample_rate: u32,
+    base_secs: f32,
+    sweep_secs: f32,
+    rate: P1,
+    depth: P2,
+    feedback: P3,
+    mix: P4,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+    P4: Pot<f32> + 'static,
+{
+    let samples_per_sec = sample_rate as f32;
+    let mut line = DelayLine::from_secs(sample_rate, base_secs + sweep_secs);
+    let mut lfo = unipolar_lfo(sample_rate, rate);
+
+    Box::new(move |sample: Sample| {
+        let delay_secs = base_secs + sweep_secs * depth.read() * lfo();
+        let wet = line.read(delay_secs * samples_per_sec - 1.0);
+        line.push(sample + feedback.read() * wet);
+        let m = mix.read();
+        (1.0 - m) * sample + m * wet
+    })
+}
+
+
+/// Chorus: thickens the signal by mixing it with a copy delayed by a slowly swept 15-30ms.
+///
+/// `rate` is the LFO frequency in Hz; a rate below 1Hz with a mix around 0.5 is typical.
+pub fn chorus<P1, P2, P3>(sample_rate: u32, rate: P1, depth: P2, mix: P3) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+{
+    swept_delay(sample_rate, CHORUS_BASE_SECS, CHORUS_SWEEP_SECS, rate, depth, 0f32, mix)
+}
+
+
+/// Flanger: the jet-plane whoosh of a comb filter swept across 0.5-5.5ms of delay.
+///
+/// `feedback` on `(-1, 1)` emphasizes the comb's teeth, with negative values giving a hollower
+/// sound.
+pub fn flanger<P1, P2, P3, P4>(
+    sample_rate: u32,
+    rate: P1,
+    depth: P2,
+    feedback: P3,
+    mix: P4,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+    P4: Pot<f32> + 'static,
+{
+    swept_delay(sample_rate, FLANGER_BASE_SECS, FLANGER_SWEEP_SECS, rate, depth, feedback, mix)
+}
+
+
+// coefficient of a first-order all-pass with the provided corner frequency
+fn all_pass_coefficient(sample_rate: u32, corner_frequency: f32) -> f32 {
+    let nyquist_limited = corner_frequency.max(1.0).min(0.49 * sample_rate as f32);
+    let t = (PI * nyquist_limited / sample_rate as f32).tan();
+    (t - 1.0) / (t + 1.0)
+}
+
+
+/// State of a single first-order all-pass section.
+#[derive(Default)]
+struct AllPassStage {
+    x1: f32,
+    y1: f32,
+}
+
+
+impl AllPassStage {
+    fn process(&mut self, sample: Sample, coefficient: f32) -> Sample {
+        let out = coefficient * sample + self.x1 - coefficient * self.y1;
+        self.x1 = sample;
+        self.y1 = flush_denormal(out);
+        out
+    }
+}
+
+
+/// First-order all-pass filter.
+///
+/// Passes all frequencies at unity gain while shifting their phase from 0 (at DC) to -180° (at
+/// Nyquist), crossing -90° at `corner_frequency`. Unlike `all_pass`, the corner can be swept
+/// smoothly.
+pub fn first_order_all_pass<P>(sample_rate: u32, corner_frequency: P) -> Filter
+where
+    P: Pot<f32> + 'static,
+{
+    let mut stage = AllPassStage::default();
+    Box::new(move |sample: Sample| {
+        stage.process(sample, all_pass_coefficient(sample_rate, corner_frequency.read()))
+    })
+}
+
+
+/// Phaser: a chain of `stages` swept first-order all-pass filters mixed back with the input.
+///
+/// Every two stages add one notch to the spectrum, so 4-12 stages is typical. The all-pass
+/// corners sweep up to four octaves above 200Hz, and `feedback` on `(-1, 1)` sharpens the notches.
+pub fn phaser<P1, P2, P3, P4>(
+    sample_rate: u32,
+    stages: usize,
+    rate: P1,
+    depth: P2,
+    feedback: P3,
+    mix: P4,
+) -> Filter
+where
+    P1: Pot<f32> + 'static,
+    P2: Pot<f32> + 'static,
+    P3: Pot<f32> + 'static,
+    P4: Pot<f32> + 'static,
+{
+    let mut chain: Vec<AllPassStage> = (0 .. stages).map(|_| AllPassStage::default()).collect();
+    let mut lfo = unipolar_lfo(sample_rate, rate);
+    let mut last = 0f32;
+
+    Box::new(move |sample: Sample| {
+        let corner = PHASER_BASE_HZ * 2f32.powf(PHASER_OCTAVES * depth.read() * lfo());
+        let coefficient = all_pass_coefficient(sample_rate, corner);
+        let input = sample + feedback.read() * last;
+        last = chain.iter_mut().fold(input, |acc, stage| stage.process(acc, coefficient));
+        let m = mix.read();
+        (1.0 - m) * sample + m * last
+    })
+}
+
+
+/// Coefficients of the two all-pass chains of the `Hilbert` transformer.
+///
+/// From Olli Niemitalo's [polyphase IIR Hilbert transformer](https://yehar.com/blog/?p=368): the
+/// chains' outputs stay within a degree of 90° apart over 20Hz-20kHz at 44.1kHz.
+const HILBERT_COEFFICIENTS: [[f32; 4]; 2] = [
+    [0.692_387_8, 0.936_065_43, 0.988_229_5, 0.998_748_85],
+    [0.402_192_12, 0.856_171_1, 0.972_290_95, 0.995_288_5],
+];
+
+
+/// Second-order all-pass section `y(t) = a²(x(t) + y(t-2)) - x(t-2)` of the `Hilbert` chains.
+#[derive(Default)]
+struct HilbertStage {
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+
+impl HilbertStage {
+    fn process(&mut self, sample: Sample, coefficient: f32) -> Sample {
+        let out = coefficient.powi(2) * (sample + self.y[1]) - self.x[1];
+        self.x = [sample, self.x[0]];
+        self.y = [flush_denormal(out), self.y[0]];
+        out
+    }
+}
+
+
+/// Splits a signal into an in-phase and a quadrature (90° shifted) component.
+#[derive(Default)]
+struct Hilbert {
+    chains: [[HilbertStage; 4]; 2],
+    delayed: f32,
+}
+
+
+impl Hilbert {
+    fn process(&mut self, sample: Sample) -> (Sample, Sample) {
+        let [ref mut chain_i, ref mut chain_q] = self.chains;
+        let [ref coefficients_i, ref coefficients_q] = HILBERT_COEFFICIENTS;
+        let i = chain_i
+            .iter_mut()
+            .zip(coefficients_i.iter())
+            .fold(sample, |acc, (stage, c)| stage.process(acc, *c));
+        let q = chain_q
+            .iter_mut()
+            .zip(coefficients_q.iter())
+            .fold(sample, |acc, (stage, c)| stage.process(acc, *c));
+        // the in-phase chain is offset by a sample to line up with the quadrature chain
+        let in_phase = self.delayed;
+        self.delayed = i;
+        (in_phase, q)
+    }
+}
+
+
+/// Frequency shifter (single-sideband modulation).
+///
+/// Moves every frequency in the stream by `shift` Hz (negative shifts down). Unlike pitch
+/// shifting, harmonic relationships are not preserved, which yields metallic, bell-like and
+/// detuned textures; shifts of a few Hz make for a slow, barber-pole phasing.
+pub fn frequency_shift<P>(sample_rate: u32, shift: P) -> Filter
+where
+    P: Pot<f32> + 'static,
+{
+    let rate = sample_rate as f32;
+    let mut hilbert = Hilbert::default();
+    let mut phase = 0f32;
+
+    Box::new(move |sample: Sample| {
+        let (in_phase, quadrature) = hilbert.process(sample);
+        phase = (phase + 2.0 * PI * shift.read() / rate).rem_euclid(2.0 * PI);
+        in_phase * phase.cos() + quadrature * phase.sin()
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    // magnitude of the provided frequency's component in `samples`
+    fn magnitude_at(samples: &[Sample], frequency: f32, sample_rate: u32) -> f32 {
+        let (re, im) = samples.iter().enumerate().fold((0f32, 0f32), |(re, im), (n, x)| {
+            let w = 2.0 * PI * frequency * n as f32 / sample_rate as f32;
+            (re + x * w.cos(), im + x * w.sin())
+        });
+        (re.powi(2) + im.powi(2)).sqrt() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_frequency_shift() {
+        let rate = 44100;
+        let mut tone = generator::sine(rate, 1000.0);
+        let mut shifter = frequency_shift(rate, 100.0);
+        let shifted: Vec<Sample> = (0 .. rate).map(|_| shifter(tone())).skip(4410).collect();
+        assert!(magnitude_at(&shifted, 1100.0, rate) > 0.45);
+        assert!(magnitude_at(&shifted, 900.0, rate) < 0.01);
+        assert!(magnitude_at(&shifted, 1000.0, rate) < 0.01);
+    }
+}
@@ -0,0 +1,72 @@
+//! Real-time pitch shifting.
+
+use std::f32::consts::PI;
+
+use crate::{Sample, Filter, Pot};
+use crate::filter::delay::DelayLine;
+
+
+/// Length of the grains crossfaded by `pitch_shift`, in seconds.
+///
+/// Longer grains smear transients, shorter grains add a rough amplitude-modulated character.
+const GRAIN_SECS: f32 = 0.05;
+
+
+/// Frequency ratio corresponding to a shift of the provided number of semitones.
+pub fn semitones_to_ratio(semitones: f32) -> f32 {
+    2f32.powf(semitones / 12.0)
+}
+
+
+/// Shift the pitch of the stream by the number of `semitones` read from the provided `Pot`
+/// without changing its duration.
+///
+/// Implemented as a granular delay-line shifter: two taps sweep through a delay line at the
+/// shifted rate, each reset by a grain length once it runs off the end of the line, and are
+/// crossfaded with complementary Hann windows so that neither reset is heard. This is cheap
+/// enough for live use (e.g. harmonizing `generator::microphone`) at the cost of some
+/// warbling on sustained tones, and adds up to one grain (50ms) of latency.
+pub fn pitch_shift<P>(sample_rate: u32, semitones: P) -> Filter
+where
+    P: Pot<f32> + 'static,
+{
+    let grain = GRAIN_SECS * sample_rate as f32;
+    let mut line = DelayLine::new(grain.ceil() as usize + 2);
+    let mut phase = 0f32;
+
+    Box::new(move |sample: Sample| {
+        line.push(sample);
+        // a read tap moving at `ratio` samples per sample sees its delay change by (1 - ratio)
+        let ratio = semitones_to_ratio(semitones.read());
+        phase = (phase + (1.0 - ratio) / grain).rem_euclid(1.0);
+        let phase_b = (phase + 0.5) % 1.0;
+
+        let window_a = 0.5 - 0.5 * (2.0 * PI * phase).cos();
+        let window_b = 0.5 - 0.5 * (2.0 * PI * phase_b).cos();
+        window_a * line.read(phase * grain) + window_b * line.read(phase_b * grain)
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    const RATE: u32 = 44100;
+
+    // zero crossings in the second half of a second of the filtered 440Hz sine, past the latency
+    fn crossings(semitones: f32) -> usize {
+        let mut sine = generator::sine(RATE, 440.0);
+        let mut shifter = pitch_shift(RATE, semitones);
+        let shifted: Vec<Sample> = (0 .. RATE).map(|_| shifter(sine())).collect();
+        let settled = &shifted[RATE as usize / 2 ..];
+        settled.windows(2).filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0)).count()
+    }
+
+    #[test]
+    fn test_octave_up_doubles_zero_crossings() {
+        let ratio = crossings(12.0) as f32 / crossings(0.0) as f32;
+        assert!((ratio - 2.0).abs() < 0.1, "ratio {}", ratio);
+    }
+}
@@ -0,0 +1,175 @@
+//! Adapters between multichannel `FrameGenerator`/`FrameFilter`s and their mono counterparts.
+//!
+//! Frames keep every channel of an instant together, so multichannel processing (stereo reverb,
+//! ping-pong delays, mid/side tricks) happens in a single closure call instead of through
+//! entangled `Generator`s synchronized behind mutexes.
+
+use std::convert::TryInto;
+
+use crate::{Sample, Generator, Filter, Frame, FrameGenerator, FrameFilter, StereoGenerator,
+    StereoFilter, Pot};
+use crate::control::mux;
+
+
+/// Apply the given `FrameFilter` to the given `FrameGenerator`, the multichannel equivalent of
+/// `filter::compose`.
+pub fn compose<const N: usize>(
+    mut generator: FrameGenerator<N>,
+    mut filter: FrameFilter<N>,
+) -> FrameGenerator<N> {
+    Box::new(move || filter(generator()))
+}
+
+
+/// Bundle one mono `Generator` per channel into a `FrameGenerator`.
+///
+/// The `Generator`s are called in channel order once per frame.
+pub fn from_channels<const N: usize>(mut generators: [Generator; N]) -> FrameGenerator<N> {
+    Box::new(move || {
+        let mut frame = [0.0; N];
+        for (value, generator) in frame.iter_mut().zip(generators.iter_mut()) {
+            *value = generator();
+        }
+        frame
+    })
+}
+
+
+/// Bundle a `left` and `right` `Generator` into a `StereoGenerator`.
+pub fn stereo(left: Generator, right: Generator) -> StereoGenerator {
+    from_channels([left, right])
+}
+
+
+/// Up-mix a mono `Generator` by copying its output to every channel.
+pub fn upmix<const N: usize>(mut generator: Generator) -> FrameGenerator<N> {
+    Box::new(move || [generator(); N])
+}
+
+
+/// Down-mix a `FrameGenerator` to mono by averaging its channels.
+pub fn downmix<const N: usize>(mut generator: FrameGenerator<N>) -> Generator {
+    Box::new(move || generator().iter().sum::<Sample>() / N as Sample)
+}
+
+
+/// Split a `FrameGenerator` into one mono `Generator` per channel.
+///
+/// The yielded `Generator`s are entangled in the same way as those yielded from
+/// `control::mux::muxn`: each yields its channel frame by frame in order however calls to the
+/// channels are interleaved.
+pub fn split<const N: usize>(mut generator: FrameGenerator<N>) -> [Generator; N] {
+    let split_frame = move |_: &[Sample], outputs: &mut [Sample]| {
+        outputs.copy_from_slice(&generator());
+    };
+    match mux::muxn(split_frame, vec![], N).try_into() {
+        Ok(channels) => channels,
+        Err(_) => unreachable!("`muxn` yields exactly as many outputs as requested"),
+    }
+}
+
+
+/// Process each channel with its own mono `Filter`.
+pub fn per_channel<const N: usize>(mut filters: [Filter; N]) -> FrameFilter<N> {
+    Box::new(move |mut frame: Frame<N>| {
+        for (value, filter) in frame.iter_mut().zip(filters.iter_mut()) {
+            *value = filter(*value);
+        }
+        frame
+    })
+}
+
+
+/// Process each channel with an independent instance of the mono `Filter` built by `make`.
+///
+/// E.g. `map_channels(|| filter::single_pole_low_pass(0.5))` low-passes every channel.
+pub fn map_channels<F, const N: usize>(mut make: F) -> FrameFilter<N>
+where
+    F: FnMut() -> Filter,
+{
+    per_channel(std::array::from_fn(|_| make()))
+}
+
+
+/// Run a mono `Filter` on the down-mix of all channels and copy its output back to every channel.
+///
+/// Useful for effects that only exist in mono but need to sit in a multichannel chain.
+pub fn mono<const N: usize>(mut filter: Filter) -> FrameFilter<N> {
+    Box::new(move |frame: Frame<N>| [filter(frame.iter().sum::<Sample>() / N as Sample); N])
+}
+
+
+/// Process the mid (`(l + r) / 2`) and side (`(l - r) / 2`) components of a stereo stream with
+/// separate mono `Filter`s.
+///
+/// E.g. compressing the mid while leaving the side untouched, or low-cutting only the side to
+/// keep the bass centered.
+pub fn mid_side(mut mid: Filter, mut side: Filter) -> StereoFilter {
+    Box::new(move |[l, r]: Frame<2>| {
+        let m = mid(0.5 * (l + r));
+        let s = side(0.5 * (l - r));
+        [m + s, m - s]
+    })
+}
+
+
+/// Adjust stereo width by scaling the side component.
+///
+/// A `width` of 0 collapses to mono, 1 leaves the stream untouched and values above 1 exaggerate
+/// the stereo image.
+pub fn width<P>(width: P) -> StereoFilter
+where
+    P: Pot<f32> + 'static,
+{
+    Box::new(move |[l, r]: Frame<2>| {
+        let m = 0.5 * (l + r);
+        let s = 0.5 * (l - r) * width.read();
+        [m + s, m - s]
+    })
+}
+
+
+/// Adjust the left/right balance of a stereo stream, with the same semantics as
+/// `control::mux::balance`.
+pub fn balance<P>(balance_pot: P) -> StereoFilter
+where
+    P: Pot<f32> + 'static,
+{
+    Box::new(move |[l, r]: Frame<2>| {
+        let balance = balance_pot.read();
+        [
+            l * (1.0 - balance).min(1.0) + r * (-balance).max(0.0),
+            l * balance.max(0.0) + r * (balance + 1.0).min(1.0),
+        ]
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    #[test]
+    fn test_split_roundtrip() {
+        let mut counter = 0f32;
+        let source: StereoGenerator = Box::new(move || {
+            counter += 1.0;
+            [counter, -counter]
+        });
+        let [left, right] = split(source);
+        let mut rejoined = stereo(left, right);
+        assert_eq!(rejoined(), [1.0, -1.0]);
+        assert_eq!(rejoined(), [2.0, -2.0]);
+    }
+
+    #[test]
+    fn test_mid_side_identity() {
+        let mut identity = mid_side(Box::new(|s| s), Box::new(|s| s));
+        assert_eq!(identity([0.25, -0.5]), [0.25, -0.5]);
+        let mut collapse = width(0.0);
+        assert_eq!(collapse([1.0, 0.0]), [0.5, 0.5]);
+        let mut mono_gen = downmix(upmix::<4>(generator::silence()));
+        assert_eq!(mono_gen(), 0.0);
+    }
+}
@@ -14,21 +14,28 @@ use crate::sampling::SampleTrack;
 
 
 /// Generate a sine wave of the provided frequency indefinitely and with maximum amplitude (-1, 1).
+///
+/// The phase is accumulated sample by sample, so the waveform stays continuous (no clicks) when
+/// the frequency changes and the frequency can itself be modulated at audio rate.
 pub fn sine<P>(sample_rate: u32, frequency: P) -> Generator
 where
     P: Pot<f32> + 'static,
 {
     let rate = sample_rate as f32;
-    let mut t = 0u64;
+    // fraction of a cycle, kept on [0, 1) to preserve precision over long runs
+    let mut phase = 0f32;
     Box::new(move || {
-        t = t + 1;
-        ((t as f32) * frequency.read() * 2.0 * PI / rate).sin()
+        phase = (phase + frequency.read() / rate).rem_euclid(1.0);
+        (2.0 * PI * phase).sin()
     })
 }
 
 
 /// Generate a square wave tone of the provided frequncy indefinitely.
-pub fn square(sample_rate: u32, frequency: f32) -> Generator {
+pub fn square<P>(sample_rate: u32, frequency: P) -> Generator
+where
+    P: Pot<f32> + 'static,
+{
     let mut gen = sine(sample_rate, frequency);
     Box::new(move || {
         let value = gen();
@@ -37,14 +44,18 @@ pub fn square(sample_rate: u32, frequency: f32) -> Generator {
 }
 
 
-/// Generate a sawtooth wave of the provided frequncy indefinitely.
-pub fn sawtooth(sample_rate: u32, frequency: f32) -> Generator {
+/// Generate a sawtooth wave of the provided frequncy indefinitely, ramping over [0, 1).
+///
+/// Like `sine`, the phase is accumulated sample by sample so the frequency can be modulated.
+pub fn sawtooth<P>(sample_rate: u32, frequency: P) -> Generator
+where
+    P: Pot<f32> + 'static,
+{
     let rate = sample_rate as f32;
-    let mut sample_clock = 0f32;
+    let mut phase = 0f32;
     Box::new(move || {
-        sample_clock = (sample_clock + 1.0) % rate;
-        let val = frequency * (sample_clock / rate);
-        val - val.floor()
+        phase = (phase + frequency.read() / rate).rem_euclid(1.0);
+        phase
     })
 }
 
@@ -0,0 +1,597 @@
+//! Audio graph of named nodes that can be rewired while it plays.
+//!
+//! A patch built from nested closures is fixed once the stream starts. A `Graph` instead holds
+//! generators, filters and mixers as named nodes joined by edges from `Output` to `Input` ports,
+//! and can be changed from a control thread while the audio thread keeps pulling samples from it.
+//!
+//! The `Graph` handle lives on the control side: it validates every change, computes the order
+//! in which the nodes are to be processed, and sends the result to the audio side over a
+//! lock-free queue. The audio side never blocks or allocates (within the documented limits) and
+//! fades edges in and out to avoid clicks when connecting, disconnecting or replacing them.
+//! Nodes and other data it is done with are sent back to be dropped on the control side, in
+//! `Graph::collect_garbage`.
+//!
+//! ```no_run
+//! # use psynth::{graph::Graph, generator, filter};
+//! let (mut graph, mut generator) = Graph::new(44100, 0.01);
+//! let tone = graph.add_generator("tone", generator::sine(44100, 440.0)).unwrap();
+//! let (lp_in, lp_out) = graph.add_filter("lp", filter::single_pole_low_pass(0.8)).unwrap();
+//! graph.connect(tone, lp_in).unwrap();
+//! graph.connect(lp_out, graph.out()).unwrap();
+//! // hand `generator` to a `Consumer`, then later, from the control thread:
+//! graph.replace(lp_in, graph.output("tone").unwrap()).unwrap();
+//! ```
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+use crate::{Sample, Generator, Filter};
+
+
+/// Maximum number of nodes held by a graph at once, including the output node.
+pub const MAX_NODES: usize = 256;
+
+
+/// Number of connections preallocated for each input port. Connecting more edges than this to a
+/// single port (counting those still fading out) allocates on the audio thread.
+const CONNECTIONS_PER_INPUT: usize = 16;
+
+
+/// Capacity of the command and garbage queues between the control and audio sides.
+const QUEUE_CAPACITY: usize = 1024;
+
+
+/// Name of the node whose output is the output of the graph.
+pub const OUT: &str = "out";
+
+
+// index of a node's slot on the audio side, assigned by the control side
+type NodeId = usize;
+
+
+// the output node is created along with the graph and always takes the first slot
+const OUT_ID: NodeId = 0;
+
+
+/// Port carrying the output of a node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Output {
+    node: NodeId,
+}
+
+
+/// Port feeding a node. A port with several incoming edges receives their sum.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Input {
+    node: NodeId,
+    port: usize,
+}
+
+
+enum NodeKind {
+    Generator(Generator),
+    Filter(Filter),
+    Mixer,
+}
+
+
+impl NodeKind {
+    fn process(&mut self, inputs: &[Sample]) -> Sample {
+        match self {
+            NodeKind::Generator(generator) => generator(),
+            NodeKind::Filter(filter) => filter(inputs[0]),
+            NodeKind::Mixer => inputs.iter().sum(),
+        }
+    }
+}
+
+
+// incoming edge of an input port with its crossfade state
+struct Connection {
+    from: NodeId,
+    gain: f32,
+    fading_out: bool,
+}
+
+
+impl Connection {
+    fn advance(&mut self, step: f32) {
+        self.gain = if self.fading_out {
+            (self.gain - step).max(0.0)
+        } else {
+            (self.gain + step).min(1.0)
+        };
+    }
+
+    fn finished(&self) -> bool {
+        self.fading_out && self.gain <= 0.0
+    }
+}
+
+
+// a node along with everything the audio side needs to process it
+struct Slot {
+    kind: NodeKind,
+    inputs: Vec<Vec<Connection>>,
+    input_values: Vec<Sample>,
+    remove_in: Option<usize>,
+}
+
+
+impl Slot {
+    fn new(kind: NodeKind, n_inputs: usize) -> Self {
+        Self {
+            kind,
+            inputs: (0 .. n_inputs).map(|_| Vec::with_capacity(CONNECTIONS_PER_INPUT)).collect(),
+            input_values: vec![0.0; n_inputs],
+            remove_in: None,
+        }
+    }
+}
+
+
+enum Command {
+    AddNode(NodeId, Box<Slot>),
+    RemoveNode(NodeId),
+    Connect(Output, Input),
+    Disconnect(Output, Input),
+    SetOrder(Vec<NodeId>),
+}
+
+
+// data released by the audio side, to be dropped on the control side
+enum Garbage {
+    Node(NodeId, Box<Slot>),
+    Order(Vec<NodeId>),
+}
+
+
+// audio side of the graph, driven by the `Generator` returned from `Graph::new`
+struct Runtime {
+    slots: Vec<Option<Box<Slot>>>,
+    outputs: Vec<Sample>,
+    order: Vec<NodeId>,
+    removing: Vec<NodeId>,
+    fade_frames: usize,
+    fade_step: f32,
+    commands: Consumer<Command>,
+    garbage: Producer<Garbage>,
+}
+
+
+impl Runtime {
+    fn discard(&mut self, garbage: Garbage) {
+        // only drops on the audio thread when the control side has stopped collecting
+        let _ = self.garbage.push(garbage);
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::AddNode(id, slot) => self.slots[id] = Some(slot),
+            Command::RemoveNode(id) => {
+                if let Some(slot) = self.slots[id].as_mut() {
+                    slot.remove_in = Some(self.fade_frames + 1);
+                    self.removing.push(id);
+                }
+            },
+            Command::Connect(from, to) => {
+                if let Some(slot) = self.slots[to.node].as_mut() {
+                    let connections = &mut slot.inputs[to.port];
+                    match connections.iter_mut().find(|c| c.from == from.node) {
+                        // reconnected before the previous fade out finished
+                        Some(connection) => connection.fading_out = false,
+                        None => connections.push(Connection {
+                            from: from.node,
+                            gain: 0.0,
+                            fading_out: false,
+                        }),
+                    }
+                }
+            },
+            Command::Disconnect(from, to) => {
+                if let Some(slot) = self.slots[to.node].as_mut() {
+                    for connection in slot.inputs[to.port].iter_mut() {
+                        if connection.from == from.node {
+                            connection.fading_out = true;
+                        }
+                    }
+                }
+            },
+            Command::SetOrder(order) => {
+                let old = std::mem::replace(&mut self.order, order);
+                self.discard(Garbage::Order(old));
+            },
+        }
+    }
+
+    fn process(&mut self, id: NodeId) {
+        let outputs = &mut self.outputs;
+        if let Some(slot) = self.slots[id].as_mut() {
+            for (connections, value) in slot.inputs.iter_mut().zip(slot.input_values.iter_mut()) {
+                *value = 0.0;
+                for connection in connections.iter_mut() {
+                    *value += connection.gain * outputs[connection.from];
+                    connection.advance(self.fade_step);
+                }
+                connections.retain(|c| !c.finished());
+            }
+            outputs[id] = slot.kind.process(&slot.input_values);
+        }
+    }
+
+    fn next_sample(&mut self) -> Sample {
+        while let Some(command) = self.commands.pop() {
+            self.apply(command);
+        }
+
+        for i in 0 .. self.order.len() {
+            self.process(self.order[i]);
+        }
+
+        // nodes being removed keep running until their outgoing edges have faded out
+        let mut i = 0;
+        while i < self.removing.len() {
+            let id = self.removing[i];
+            self.process(id);
+            let done = match self.slots[id].as_mut().and_then(|slot| slot.remove_in.as_mut()) {
+                Some(remaining) => {
+                    *remaining -= 1;
+                    *remaining == 0
+                },
+                None => true,
+            };
+            if done {
+                self.removing.swap_remove(i);
+                self.outputs[id] = 0.0;
+                if let Some(slot) = self.slots[id].take() {
+                    self.discard(Garbage::Node(id, slot));
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        self.outputs[OUT_ID]
+    }
+}
+
+
+// edge from an output to an input, as recorded on the control side
+type Edge = (Output, Input);
+
+
+// control-side record of a node
+struct NodeInfo {
+    id: NodeId,
+    n_inputs: usize,
+}
+
+
+/// Control-side handle to a running audio graph.
+pub struct Graph {
+    nodes: HashMap<String, NodeInfo>,
+    edges: Vec<Edge>,
+    free_ids: Vec<NodeId>,
+    commands: Producer<Command>,
+    garbage: Consumer<Garbage>,
+}
+
+
+impl Graph {
+    /// Create an empty graph whose edges fade in and out over `fade_secs` seconds.
+    ///
+    /// Returns the control handle along with the `Generator` yielding the output of the graph,
+    /// which is to be handed to a `Consumer` on the audio thread. The graph holds a single mixer
+    /// node named `"out"` to begin with; whatever is connected to it is heard.
+    pub fn new(sample_rate: u32, fade_secs: f32) -> (Self, Generator) {
+        let (command_producer, command_consumer) = RingBuffer::new(QUEUE_CAPACITY).split();
+        let (garbage_producer, garbage_consumer) = RingBuffer::new(QUEUE_CAPACITY).split();
+        let fade_frames = (fade_secs * sample_rate as f32).round() as usize;
+
+        let mut slots: Vec<Option<Box<Slot>>> = (0 .. MAX_NODES).map(|_| None).collect();
+        slots[OUT_ID] = Some(Box::new(Slot::new(NodeKind::Mixer, 1)));
+        let mut order = Vec::with_capacity(MAX_NODES);
+        order.push(OUT_ID);
+
+        let mut runtime = Runtime {
+            slots,
+            outputs: vec![0.0; MAX_NODES],
+            order,
+            removing: Vec::with_capacity(MAX_NODES),
+            fade_frames,
+            fade_step: 1.0 / fade_frames.max(1) as f32,
+            commands: command_consumer,
+            garbage: garbage_producer,
+        };
+
+        let mut nodes = HashMap::new();
+        nodes.insert(OUT.to_string(), NodeInfo { id: OUT_ID, n_inputs: 1 });
+        let graph = Self {
+            nodes,
+            edges: Vec::new(),
+            free_ids: (OUT_ID + 1 .. MAX_NODES).rev().collect(),
+            commands: command_producer,
+            garbage: garbage_consumer,
+        };
+        (graph, Box::new(move || runtime.next_sample()))
+    }
+
+    /// Drop everything the audio side is done with. Called by every method that changes the
+    /// graph; call it periodically when the graph is left unchanged for long stretches.
+    pub fn collect_garbage(&mut self) {
+        while let Some(garbage) = self.garbage.pop() {
+            match garbage {
+                Garbage::Node(id, slot) => {
+                    drop(slot);
+                    self.free_ids.push(id);
+                },
+                Garbage::Order(order) => drop(order),
+            }
+        }
+    }
+
+    /// Input port of the node whose output is the output of the graph.
+    pub fn out(&self) -> Input {
+        Input { node: OUT_ID, port: 0 }
+    }
+
+    /// Output port of the node with the provided name.
+    pub fn output(&self, name: &str) -> Result<Output> {
+        Ok(Output { node: self.info(name)?.id })
+    }
+
+    /// Input port number `port` of the node with the provided name.
+    pub fn input(&self, name: &str, port: usize) -> Result<Input> {
+        let info = self.info(name)?;
+        if port >= info.n_inputs {
+            return Err(anyhow!(
+                "node '{}' has {} input ports, no port {}", name, info.n_inputs, port
+            ));
+        }
+        Ok(Input { node: info.id, port })
+    }
+
+    /// Add a source node.
+    pub fn add_generator(&mut self, name: &str, generator: Generator) -> Result<Output> {
+        let id = self.add_node(name, NodeKind::Generator(generator), 0)?;
+        Ok(Output { node: id })
+    }
+
+    /// Add a node applying a `Filter` to its single input.
+    pub fn add_filter(&mut self, name: &str, filter: Filter) -> Result<(Input, Output)> {
+        let id = self.add_node(name, NodeKind::Filter(filter), 1)?;
+        Ok((Input { node: id, port: 0 }, Output { node: id }))
+    }
+
+    /// Add a node summing `n_inputs` input ports, e.g. to give each of several sources its own
+    /// port that can be `replace`d independently.
+    pub fn add_mixer(&mut self, name: &str, n_inputs: usize) -> Result<(Vec<Input>, Output)> {
+        let id = self.add_node(name, NodeKind::Mixer, n_inputs)?;
+        let inputs = (0 .. n_inputs).map(|port| Input { node: id, port }).collect();
+        Ok((inputs, Output { node: id }))
+    }
+
+    /// Remove the named node, fading out the edges leaving it.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.collect_garbage();
+        if name == OUT {
+            return Err(anyhow!("the output node can't be removed"));
+        }
+        let id = self.info(name)?.id;
+        let outgoing: Vec<Edge> = self.edges
+            .iter()
+            .filter(|(from, to)| from.node == id && to.node != id)
+            .cloned()
+            .collect();
+        let remaining: Vec<Edge> = self.edges
+            .iter()
+            .filter(|(from, to)| from.node != id && to.node != id)
+            .cloned()
+            .collect();
+        let order = self.order(&remaining, Some(id))?;
+
+        let mut commands: Vec<Command> = outgoing
+            .iter()
+            .map(|&(from, to)| Command::Disconnect(from, to))
+            .collect();
+        commands.push(Command::RemoveNode(id));
+        commands.push(Command::SetOrder(order));
+        self.send(commands)?;
+
+        self.nodes.remove(name);
+        self.edges = remaining;
+        Ok(())
+    }
+
+    /// Connect an output to an input, fading the new edge in.
+    ///
+    /// Fails if the edge already exists or would create a cycle.
+    pub fn connect(&mut self, from: Output, to: Input) -> Result<()> {
+        self.collect_garbage();
+        self.check_ports(from, to)?;
+        if self.edges.contains(&(from, to)) {
+            return Err(anyhow!(
+                "{} is already connected to {}", self.name(from.node), self.name(to.node)
+            ));
+        }
+        let mut edges = self.edges.clone();
+        edges.push((from, to));
+        let order = self.order(&edges, None)?;
+        self.send(vec![Command::Connect(from, to), Command::SetOrder(order)])?;
+        self.edges = edges;
+        Ok(())
+    }
+
+    /// Disconnect an output from an input, fading the edge out.
+    pub fn disconnect(&mut self, from: Output, to: Input) -> Result<()> {
+        self.collect_garbage();
+        if !self.edges.contains(&(from, to)) {
+            return Err(anyhow!(
+                "{} is not connected to {}", self.name(from.node), self.name(to.node)
+            ));
+        }
+        self.send(vec![Command::Disconnect(from, to)])?;
+        self.edges.retain(|&edge| edge != (from, to));
+        Ok(())
+    }
+
+    /// Replace whatever feeds the provided input with `from`, crossfading between the two.
+    pub fn replace(&mut self, to: Input, from: Output) -> Result<()> {
+        self.collect_garbage();
+        self.check_ports(from, to)?;
+        let (replaced, mut edges): (Vec<Edge>, Vec<Edge>) = self.edges
+            .iter()
+            .partition(|&&(f, t)| t == to && f != from);
+        if !edges.contains(&(from, to)) {
+            edges.push((from, to));
+        }
+        let order = self.order(&edges, None)?;
+
+        let mut commands: Vec<Command> = replaced
+            .iter()
+            .map(|&(f, t)| Command::Disconnect(f, t))
+            .collect();
+        commands.push(Command::Connect(from, to));
+        commands.push(Command::SetOrder(order));
+        self.send(commands)?;
+        self.edges = edges;
+        Ok(())
+    }
+
+    fn info(&self, name: &str) -> Result<&NodeInfo> {
+        self.nodes.get(name).ok_or_else(|| anyhow!("no node named '{}' in the graph", name))
+    }
+
+    // name of the node with the provided id, for error messages
+    fn name(&self, id: NodeId) -> String {
+        self.nodes
+            .iter()
+            .find(|(_, info)| info.id == id)
+            .map(|(name, _)| format!("'{}'", name))
+            .unwrap_or_else(|| format!("<removed node {}>", id))
+    }
+
+    fn check_ports(&self, from: Output, to: Input) -> Result<()> {
+        let exists = |id: NodeId| self.nodes.values().any(|info| info.id == id);
+        if !exists(from.node) || !exists(to.node) {
+            return Err(anyhow!("port of a node that has been removed from the graph"));
+        }
+        Ok(())
+    }
+
+    fn add_node(&mut self, name: &str, kind: NodeKind, n_inputs: usize) -> Result<NodeId> {
+        self.collect_garbage();
+        if self.nodes.contains_key(name) {
+            return Err(anyhow!("a node named '{}' already exists", name));
+        }
+        let id = *self.free_ids
+            .last()
+            .ok_or_else(|| anyhow!("graph is full ({} nodes)", MAX_NODES))?;
+
+        self.nodes.insert(name.to_string(), NodeInfo { id, n_inputs });
+        let order = self.order(&self.edges, None);
+        self.nodes.remove(name);
+        let slot = Box::new(Slot::new(kind, n_inputs));
+        self.send(vec![Command::AddNode(id, slot), Command::SetOrder(order?)])?;
+
+        self.free_ids.pop();
+        self.nodes.insert(name.to_string(), NodeInfo { id, n_inputs });
+        Ok(id)
+    }
+
+    // processing order of the nodes such that every node comes after those feeding it, leaving
+    // out `excluded`; fails if the edges form a cycle
+    fn order(&self, edges: &[Edge], excluded: Option<NodeId>) -> Result<Vec<NodeId>> {
+        let ids: Vec<NodeId> = self.nodes
+            .values()
+            .map(|info| info.id)
+            .filter(|&id| Some(id) != excluded)
+            .collect();
+        let mut n_incoming: HashMap<NodeId, usize> = ids.iter().map(|&id| (id, 0)).collect();
+        for (_, to) in edges {
+            *n_incoming.entry(to.node).or_insert(0) += 1;
+        }
+
+        // Kahn's algorithm
+        let mut ready: Vec<NodeId> = ids.iter().cloned().filter(|id| n_incoming[id] == 0).collect();
+        let mut order = Vec::with_capacity(MAX_NODES);
+        while let Some(id) = ready.pop() {
+            order.push(id);
+            for (_, to) in edges.iter().filter(|(from, _)| from.node == id) {
+                let count = n_incoming.get_mut(&to.node).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(to.node);
+                }
+            }
+        }
+        if order.len() < ids.len() {
+            return Err(anyhow!("connection would create a cycle in the graph"));
+        }
+        Ok(order)
+    }
+
+    fn send(&mut self, commands: Vec<Command>) -> Result<()> {
+        if self.commands.remaining() < commands.len() {
+            return Err(anyhow!("graph command queue is full -- is the audio thread running?"));
+        }
+        for command in commands {
+            // can't fail: the queue has a single producer and was checked for room above
+            let _ = self.commands.push(command);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn constant(value: Sample) -> Generator {
+        Box::new(move || value)
+    }
+
+    #[test]
+    fn test_connect_and_crossfade() {
+        let (mut graph, mut generator) = Graph::new(100, 0.04);
+        let a = graph.add_generator("a", constant(1.0)).unwrap();
+        let b = graph.add_generator("b", constant(-1.0)).unwrap();
+        let (gain_in, gain_out) = graph.add_filter("gain", Box::new(|s| 0.5 * s)).unwrap();
+        graph.connect(a, gain_in).unwrap();
+        graph.connect(gain_out, graph.out()).unwrap();
+        let faded_in: Vec<Sample> = (0 .. 6).map(|_| generator()).collect();
+        assert_eq!(faded_in[0], 0.0);
+        assert_eq!(faded_in[5], 0.5);
+
+        // crossfade from `a` to `b`, passing through silence halfway
+        graph.replace(gain_in, b).unwrap();
+        let crossfade: Vec<Sample> = (0 .. 6).map(|_| generator()).collect();
+        assert!(crossfade[2].abs() < 1e-6);
+        assert_eq!(crossfade[5], -0.5);
+
+        assert!(graph.connect(gain_out, gain_in).is_err());
+        assert!(graph.add_generator("a", constant(0.0)).is_err());
+        assert!(graph.input("gain", 1).is_err());
+    }
+
+    #[test]
+    fn test_remove_returns_node() {
+        let (mut graph, mut generator) = Graph::new(100, 0.02);
+        let a = graph.add_generator("a", constant(1.0)).unwrap();
+        graph.connect(a, graph.out()).unwrap();
+        for _ in 0 .. 4 {
+            generator();
+        }
+        graph.remove("a").unwrap();
+        let tail: Vec<Sample> = (0 .. 4).map(|_| generator()).collect();
+        assert_eq!(tail, vec![1.0, 0.5, 0.0, 0.0]);
+
+        let n_free = graph.free_ids.len();
+        graph.collect_garbage();
+        assert_eq!(graph.free_ids.len(), n_free + 1);
+        assert!(graph.output("a").is_err());
+    }
+}
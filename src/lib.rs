@@ -6,6 +6,12 @@ pub mod observer;
 pub mod control;
 pub mod sampling;
 pub mod device;
+pub mod frame;
+pub mod block;
+pub mod graph;
+pub mod context;
+pub mod render;
+pub mod patch;
 
 
 /// Audio out value at a given instant.
@@ -33,6 +39,26 @@ pub type Generator = Box<dyn FnMut() -> Sample + Send>;
 pub type Filter = Box<dyn FnMut(Sample) -> Sample + Send>;
 
 
+/// Audio out values of all channels at a given instant, e.g. `[left, right]` for stereo.
+pub type Frame<const N: usize> = [Sample; N];
+
+
+/// Source of an `N`-channel audio stream, the multichannel equivalent of a `Generator`.
+///
+/// Each call yields the values of all `N` channels for one instant. See `frame` for adapters
+/// to and from mono `Generator`s.
+pub type FrameGenerator<const N: usize> = Box<dyn FnMut() -> Frame<N> + Send>;
+
+
+/// Transformation applied to an `N`-channel audio stream, the multichannel equivalent of a
+/// `Filter`.
+pub type FrameFilter<const N: usize> = Box<dyn FnMut(Frame<N>) -> Frame<N> + Send>;
+
+
+pub type StereoGenerator = FrameGenerator<2>;
+pub type StereoFilter = FrameFilter<2>;
+
+
 /// End consumer of an audio stream.
 ///
 /// Calls the `Generator` repeatedly to generate the audio stream them does some implementation-
@@ -46,8 +72,20 @@ pub trait Consumer: Send {
 
 
 /// Passive observer on the stream received by a `Consumer`.
+///
+/// Observers run on their own thread, fed by the `Consumer` through an `observer::ObserverTap`.
 pub trait Observer {
     fn sample(&mut self, sample: Sample);
+
+    /// Observe a block of frames of `channels` interleaved samples each.
+    ///
+    /// Defaults to observing each sample in turn.
+    fn frames(&mut self, frames: &[Sample], channels: usize) {
+        let _ = channels;
+        for &sample in frames.iter() {
+            self.sample(sample);
+        }
+    }
 }
 
 
@@ -95,3 +133,17 @@ impl FilterComposable for Generator {
         join_function(left_gen, right_gen)
     }
 }
+
+
+/// Multichannel equivalent of `FilterComposable`, enabling the builder pattern on
+/// `FrameGenerator`s.
+pub trait FrameComposable<const N: usize> {
+    fn compose(self, filter: FrameFilter<N>) -> FrameGenerator<N>;
+}
+
+
+impl<const N: usize> FrameComposable<N> for FrameGenerator<N> {
+    fn compose(self, filter: FrameFilter<N>) -> FrameGenerator<N> {
+        frame::compose(self, filter)
+    }
+}
@@ -1,6 +1,130 @@
-use std::io::{Stdout, Write};
+use std::fs::File;
+use std::io::{BufWriter, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use crate::{Observer, Sample};
+use anyhow::{anyhow, Result};
+use hound::{WavSpec, SampleFormat};
+use ringbuf::{Producer, RingBuffer};
+
+use crate::{Observer, Sample, Frame};
+
+
+/// Maximum number of frames handed to `Observer`s at a time.
+pub const OBSERVER_BLOCK_FRAMES: usize = 512;
+
+
+/// Number of frames queued for the observer thread before new frames are dropped.
+pub const OBSERVER_QUEUE_FRAMES: usize = 1 << 16;
+
+
+/// Interval at which the observer thread checks an empty queue for frames.
+const OBSERVER_POLL: Duration = Duration::from_millis(5);
+
+
+/// Audio side of the pipeline feeding `Observer`s from a `Consumer`.
+///
+/// Frames pushed by the `Consumer` are copied into a lock-free ring buffer allocated up front,
+/// and a dedicated thread hands them to the `Observer`s in blocks. Pushing never allocates or
+/// blocks: frames that don't fit because the observers have fallen behind are dropped, counted
+/// and reported on stderr. Dropping the tap lets the thread drain the queue and drop the
+/// observers (finalizing e.g. a `WavWriter`) before returning.
+pub struct ObserverTap {
+    producer: Producer<Sample>,
+    channels: usize,
+    dropped: Arc<AtomicU64>,
+    closed: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+
+impl ObserverTap {
+    /// Spawn the observer thread for a stream of `channels` interleaved channels.
+    pub fn spawn(channels: usize, mut observers: Vec<Box<dyn Observer + Send>>) -> Self {
+        let channels = channels.max(1);
+        let (producer, mut consumer) = RingBuffer::new(OBSERVER_QUEUE_FRAMES * channels).split();
+        let dropped = Arc::new(AtomicU64::new(0));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let dropped = Arc::clone(&dropped);
+            let closed = Arc::clone(&closed);
+            thread::spawn(move || {
+                let mut block = vec![0.0; OBSERVER_BLOCK_FRAMES * channels];
+                let mut reported = 0;
+                loop {
+                    // checked before draining so that frames pushed before closing are observed
+                    let finished = closed.load(Ordering::Acquire);
+                    let available = consumer.len() / channels * channels;
+                    if available == 0 {
+                        if finished {
+                            break;
+                        }
+                        thread::sleep(OBSERVER_POLL);
+                        continue;
+                    }
+                    let wanted = available.min(block.len());
+                    let n = consumer.pop_slice(&mut block[.. wanted]);
+                    for observer in observers.iter_mut() {
+                        observer.frames(&block[.. n], channels);
+                    }
+                    let total = dropped.load(Ordering::Relaxed);
+                    if total > reported {
+                        eprintln!("observers fell behind, {} frames dropped", total - reported);
+                        reported = total;
+                    }
+                }
+            })
+        };
+
+        Self { producer, channels, dropped, closed, thread: Some(thread) }
+    }
+
+    /// Queue interleaved frames for the observers, dropping them all if they don't fit.
+    pub fn push(&mut self, frames: &[Sample]) {
+        if self.producer.remaining() < frames.len() {
+            self.drop_frames(frames.len() / self.channels);
+        } else {
+            self.producer.push_slice(frames);
+        }
+    }
+
+    /// Queue `N`-channel frames for the observers, dropping them all if they don't fit.
+    pub fn push_frames<const N: usize>(&mut self, frames: &[Frame<N>]) {
+        if self.producer.remaining() < frames.len() * N {
+            self.drop_frames(frames.len());
+        } else {
+            for frame in frames.iter() {
+                self.producer.push_slice(frame);
+            }
+        }
+    }
+
+    // count frames that didn't fit in the queue
+    fn drop_frames(&self, n_frames: usize) {
+        self.dropped.fetch_add(n_frames as u64, Ordering::Relaxed);
+    }
+
+    /// Number of frames dropped so far because the observers fell behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+
+impl Drop for ObserverTap {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("observer thread panicked");
+            }
+        }
+    }
+}
 
 
 /// Dump all sample values to stdout.
@@ -12,10 +136,394 @@ impl Observer for Stdout {
 }
 
 
-// TODO: implement
-pub struct WavWriter {}
+/// Encoding of the samples written by a `WavWriter`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+
+impl WavFormat {
+    pub fn bits_per_sample(&self) -> u16 {
+        match self {
+            WavFormat::Int16 => 16,
+            WavFormat::Int24 => 24,
+            WavFormat::Int32 | WavFormat::Float32 => 32,
+        }
+    }
+
+    fn sample_format(&self) -> SampleFormat {
+        match self {
+            WavFormat::Float32 => SampleFormat::Float,
+            _ => SampleFormat::Int,
+        }
+    }
+
+    // integer formats clip the stream to [-1, 1]; floats are written as they are
+    fn write<W>(&self, writer: &mut hound::WavWriter<W>, sample: Sample) -> hound::Result<()>
+    where
+        W: std::io::Write + std::io::Seek,
+    {
+        let clipped = sample.clamp(-1.0, 1.0) as f64;
+        match self {
+            WavFormat::Int16 => writer.write_sample((clipped * i16::MAX as f64) as i16),
+            WavFormat::Int24 => writer.write_sample((clipped * 8_388_607.0) as i32),
+            WavFormat::Int32 => writer.write_sample((clipped * i32::MAX as f64) as i32),
+            WavFormat::Float32 => writer.write_sample(sample),
+        }
+    }
+}
+
+
+/// When a `WavWriter` moves on to a new file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rotation {
+    /// Record everything to a single file.
+    Never,
+    /// Start a new file before one grows past this many bytes.
+    Size(u64),
+    /// Start a new file once one holds this much audio.
+    Duration(Duration),
+}
+
+
+/// Settings of a `WavWriter`. `WavOptions::new` records 32-bit float to a single file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WavOptions {
+    pub sample_rate: u32,
+    /// Number of channels interleaved in the observed stream.
+    pub channels: u16,
+    pub format: WavFormat,
+    pub rotation: Rotation,
+}
+
+
+impl WavOptions {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            format: WavFormat::Float32,
+            rotation: Rotation::Never,
+        }
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: self.format.bits_per_sample(),
+            sample_format: self.format.sample_format(),
+        }
+    }
+
+    // size of the RIFF, fmt and data chunk headers written by hound, which switches to the larger
+    // WAVE_FORMAT_EXTENSIBLE fmt chunk past 2 channels or 16 bits
+    fn header_bytes(&self) -> u64 {
+        let spec = self.spec();
+        if spec.channels > 2 || spec.bits_per_sample > 16 { 68 } else { 44 }
+    }
+
+    // number of frames after which to start a new file, if any
+    fn frames_per_file(&self) -> Option<u64> {
+        let frame_bytes = self.channels as u64 * self.format.bits_per_sample() as u64 / 8;
+        let frames = match self.rotation {
+            Rotation::Never => return None,
+            Rotation::Size(bytes) => bytes.saturating_sub(self.header_bytes()) / frame_bytes,
+            Rotation::Duration(duration) => {
+                (duration.as_secs_f64() * self.sample_rate as f64) as u64
+            },
+        };
+        Some(frames.max(1))
+    }
+}
+
+
+// state of a recording, shared with `finalize_all`
+struct Recording {
+    options: WavOptions,
+    path: PathBuf,
+    file: Option<hound::WavWriter<BufWriter<File>>>,
+    closed: bool,
+    index: usize,
+    frames_in_file: u64,
+    channel: u16,
+}
+
+
+impl Recording {
+    // path of the file currently recorded to: numbered when rotating, e.g. `jam-0002.wav`
+    fn current_path(&self) -> PathBuf {
+        if self.options.rotation == Rotation::Never {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().and_then(|s| s.to_str()).unwrap_or("psynth");
+        let name = match self.path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("{}-{:04}.{}", stem, self.index, extension),
+            None => format!("{}-{:04}", stem, self.index),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn open(&mut self) -> Result<()> {
+        self.file = Some(hound::WavWriter::create(self.current_path(), self.options.spec())?);
+        self.frames_in_file = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, sample: Sample) -> Result<()> {
+        if self.channel == 0 {
+            if let Some(limit) = self.options.frames_per_file() {
+                if self.frames_in_file >= limit {
+                    self.finalize()?;
+                    self.index += 1;
+                    self.open()?;
+                }
+            }
+        }
+        let file = self.file.as_mut().ok_or_else(|| anyhow!("WAV file is not open"))?;
+        self.options.format.write(file, sample)?;
+        self.channel += 1;
+        if self.channel == self.options.channels {
+            self.channel = 0;
+            self.frames_in_file += 1;
+        }
+        Ok(())
+    }
+
+    fn write_frames(&mut self, frames: &[Sample], channels: usize) -> Result<()> {
+        if channels != self.options.channels as usize {
+            return Err(anyhow!(
+                "observed {} channels but recording {}", channels, self.options.channels
+            ));
+        }
+        for &sample in frames.iter() {
+            self.write(sample)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        match self.file.take() {
+            Some(file) => Ok(file.finalize()?),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Err(e) = self.finalize() {
+            eprintln!("failed to finalize '{}': {}", self.current_path().display(), e);
+        }
+    }
+}
+
+
+// recordings to finalize when the process is interrupted
+static RECORDINGS: Mutex<Vec<Weak<Mutex<Recording>>>> = Mutex::new(Vec::new());
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+
+/// Finalize every open `WavWriter`, which stop recording.
+///
+/// Called on Ctrl-C by the handler of `install_interrupt_handler`. Programs installing their own
+/// Ctrl-C handler (only one is allowed per process) should call this from it instead.
+pub fn finalize_all() {
+    let recordings = RECORDINGS.lock().unwrap_or_else(|e| e.into_inner());
+    for recording in recordings.iter().filter_map(Weak::upgrade) {
+        recording.lock().unwrap_or_else(|e| e.into_inner()).close();
+    }
+}
+
+
+/// Install a Ctrl-C handler finalizing every open `WavWriter` and raising `interrupted`.
+///
+/// The handler doesn't exit the process: programs should poll `interrupted` and return from
+/// `main` once it is set, so that audio streams are stopped before exiting. Fails if a Ctrl-C
+/// handler is already installed.
+pub fn install_interrupt_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        finalize_all();
+        INTERRUPTED.store(true, Ordering::Release);
+    })?;
+    Ok(())
+}
+
+
+/// Whether Ctrl-C was received since `install_interrupt_handler`.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Acquire)
+}
+
+
+/// Record the observed stream to WAV files.
+///
+/// The observed stream must have `options.channels` channels. Files are finalized
+/// (their headers written) when the `WavWriter` is dropped and by `finalize_all`, which
+/// `install_interrupt_handler` calls on Ctrl-C, so a recording survives a session ended either
+/// way. Write errors are reported on stderr and
+/// stop the recording rather than the stream.
+pub struct WavWriter {
+    recording: Arc<Mutex<Recording>>,
+}
+
+
+impl WavWriter {
+    /// Start recording to `path`, or to numbered files next to it when rotating.
+    pub fn create<P>(path: P, options: WavOptions) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        if options.channels == 0 {
+            return Err(anyhow!("unable to record audio with 0 channels"));
+        }
+        let mut recording = Recording {
+            options,
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            closed: false,
+            index: 0,
+            frames_in_file: 0,
+            channel: 0,
+        };
+        recording.open()?;
+        let recording = Arc::new(Mutex::new(recording));
+
+        let mut recordings = RECORDINGS.lock().unwrap_or_else(|e| e.into_inner());
+        recordings.retain(|r| r.strong_count() > 0);
+        recordings.push(Arc::downgrade(&recording));
+
+        Ok(Self { recording })
+    }
+
+    // apply `write` to the recording unless it has stopped, stopping it if `write` fails
+    fn record<F>(&self, write: F)
+    where
+        F: FnOnce(&mut Recording) -> Result<()>,
+    {
+        let mut recording = self.recording.lock().unwrap_or_else(|e| e.into_inner());
+        if recording.closed {
+            return;
+        }
+        if let Err(e) = write(&mut recording) {
+            eprintln!("stopped recording to '{}': {}", recording.current_path().display(), e);
+            recording.close();
+        }
+    }
+
+    /// Finalize the current file and stop recording.
+    pub fn finalize(self) -> Result<()> {
+        let mut recording = self.recording.lock().unwrap_or_else(|e| e.into_inner());
+        recording.closed = true;
+        recording.finalize()
+    }
+}
+
+
 impl Observer for WavWriter {
     fn sample(&mut self, sample: Sample) {
-        unimplemented!("{}", sample)
+        self.record(|recording| recording.write(sample));
+    }
+
+    /// Record the observed frames, stopping the recording if they don't have the channel count
+    /// it was created with.
+    fn frames(&mut self, frames: &[Sample], channels: usize) {
+        self.record(|recording| recording.write_frames(frames, channels));
+    }
+}
+
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        self.recording.lock().unwrap_or_else(|e| e.into_inner()).close();
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wav_writer_rotation() {
+        let path = std::env::temp_dir().join("psynth-test-observer.wav");
+        let mut options = WavOptions::new(100, 2);
+        options.format = WavFormat::Int16;
+        options.rotation = Rotation::Duration(Duration::from_millis(500));
+
+        let mut writer = WavWriter::create(&path, options).unwrap();
+        for i in 0 .. 160 {
+            writer.sample(if i % 2 == 0 { 0.5 } else { -2.0 });
+        }
+        drop(writer);
+
+        let lengths: Vec<usize> = (0 .. 2)
+            .map(|i| {
+                let rotated = path.with_file_name(format!("psynth-test-observer-{:04}.wav", i));
+                let mut reader = hound::WavReader::open(&rotated).unwrap();
+                assert_eq!(reader.spec(), options.spec());
+                let samples: Vec<i16> = reader.samples().map(|s| s.unwrap()).collect();
+                std::fs::remove_file(&rotated).unwrap();
+                assert_eq!(&samples[.. 2], &[i16::MAX / 2, -i16::MAX]);
+                samples.len()
+            })
+            .collect();
+        assert_eq!(lengths, vec![100, 60]);
+    }
+
+    #[test]
+    fn test_wav_writer_size_rotation() {
+        let path = std::env::temp_dir().join("psynth-test-observer-size.wav");
+        let mut options = WavOptions::new(100, 2);
+        options.rotation = Rotation::Size(1000);
+
+        let mut writer = WavWriter::create(&path, options).unwrap();
+        for _ in 0 .. 400 {
+            writer.sample(0.5);
+        }
+        drop(writer);
+
+        let sizes: Vec<u64> = (0 .. 2)
+            .map(|i| {
+                let name = format!("psynth-test-observer-size-{:04}.wav", i);
+                let rotated = path.with_file_name(name);
+                let size = std::fs::metadata(&rotated).unwrap().len();
+                std::fs::remove_file(&rotated).unwrap();
+                size
+            })
+            .collect();
+        assert!(sizes.iter().all(|&size| size <= 1000), "{:?}", sizes);
+        assert_eq!(sizes[0], 68 + 116 * 8);
+    }
+
+    struct Collect(Arc<Mutex<Vec<(usize, Sample)>>>);
+
+    impl Observer for Collect {
+        fn sample(&mut self, sample: Sample) {
+            self.0.lock().unwrap().push((1, sample));
+        }
+
+        fn frames(&mut self, frames: &[Sample], channels: usize) {
+            let mut observed = self.0.lock().unwrap();
+            observed.extend(frames.iter().map(|&sample| (channels, sample)));
+        }
+    }
+
+    #[test]
+    fn test_observer_tap() {
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let mut tap = ObserverTap::spawn(2, vec![Box::new(Collect(Arc::clone(&observed)))]);
+        tap.push(&[1.0, 2.0]);
+        tap.push_frames(&[[3.0, 4.0], [5.0, 6.0]]);
+        tap.push(&vec![0.0; 2 * OBSERVER_QUEUE_FRAMES + 2]);
+        assert_eq!(tap.dropped_frames(), OBSERVER_QUEUE_FRAMES as u64 + 1);
+        drop(tap);
+
+        let observed = observed.lock().unwrap();
+        let expected: Vec<(usize, Sample)> = (1 ..= 6).map(|i| (2, i as Sample)).collect();
+        assert_eq!(*observed, expected);
     }
 }
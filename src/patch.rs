@@ -0,0 +1,615 @@
+//! Declarative patches, loaded from TOML files.
+//!
+//! A patch names a set of generators, each with a chain of filters, and a consumer section
+//! routing some of them to the output. Parameters are either constants or pots driven by another
+//! generator of the patch:
+//!
+//! ```toml
+//! [generators.lfo]
+//! type = "sine"
+//! frequency = 0.5
+//!
+//! [generators.tone]
+//! type = "sine"
+//! frequency = { generator = "lfo", low = 220.0, high = 440.0 }
+//! filters = [
+//!     { type = "low_pass", cutoff = 2000.0, resonance = 0.3 },
+//!     { type = "gain", factor = 0.1 },
+//! ]
+//!
+//! [consumer]
+//! type = "stereo"
+//! left = "tone"
+//! right = "tone"
+//! balance = { generator = "lfo" }
+//! ```
+//!
+//! A `multi` consumer routes any number of generators onto a device with more channels, e.g.
+//! `{ type = "multi", channels = 4, inputs = ["a", "b"], routing = [[1, 0], [0, 1], [1, 0],
+//! [0, 1]] }` with one row of input gains per output channel (see `consumer::Routing`).
+//!
+//! Every reference to a generator builds its own copy of it, so e.g. two references to a `white`
+//! noise generator are uncorrelated. A `Swapper` replaces the patch being played with a crossfade,
+//! so that an edited file can be reloaded without restarting the stream.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use ringbuf::{Producer, RingBuffer};
+use serde::Deserialize;
+
+use crate::{generator, filter, Sample, Generator, Filter, Consumer, Pot};
+use crate::consumer::{MonoConsumer, StereoConsumer, MultiChannelConsumer, Routing};
+use crate::control::{mux, pot::GeneratorPot};
+use crate::filter::resonant::SvfMode;
+use crate::sampling::VecTrack;
+
+
+/// Duration of the crossfade between a patch and the one replacing it.
+pub const PATCH_FADE_SECS: f32 = 0.05;
+
+
+/// Value of a parameter: a constant, or a generator of the patch mapped from `[-1, 1]` onto
+/// `[low, high]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Param {
+    Value(f32),
+    Generator {
+        generator: String,
+        #[serde(default = "default_low")]
+        low: f32,
+        #[serde(default = "default_high")]
+        high: f32,
+    },
+}
+
+
+fn default_low() -> f32 {
+    -1.0
+}
+
+
+fn default_high() -> f32 {
+    1.0
+}
+
+
+/// Source of a named generator.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Sine { frequency: Param },
+    Square { frequency: Param },
+    Sawtooth { frequency: Param },
+    White,
+    Silence,
+    /// Loop a WAV file recorded at the output sample rate. A relative `path` is resolved against
+    /// the directory of the patch file.
+    Sample { path: String },
+    /// Sum of other generators, each scaled by the gain at the same position (unity if missing).
+    Mix {
+        inputs: Vec<String>,
+        #[serde(default)]
+        gains: Vec<f32>,
+    },
+}
+
+
+/// Filter applied to a generator.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterSpec {
+    Gain { factor: Param },
+    Offset { offset: Param },
+    Clip { low: Param, high: Param },
+    Warble { period: f32 },
+    RampUp { secs: f32 },
+    Comb {
+        delay_secs: f32,
+        decay: f32,
+        #[serde(default)]
+        feedback: bool,
+    },
+    AllPass { delay_secs: f32, decay: f32 },
+    Reverb,
+    DcBlock {
+        #[serde(default = "default_dc_block_hz")]
+        cutoff: f32,
+    },
+    LowPass { cutoff: Param, resonance: Param },
+    HighPass { cutoff: Param, resonance: Param },
+    BandPass { cutoff: Param, resonance: Param },
+    Notch { cutoff: Param, resonance: Param },
+    Ladder { cutoff: Param, resonance: Param },
+}
+
+
+fn default_dc_block_hz() -> f32 {
+    filter::DC_BLOCK_HZ
+}
+
+
+/// Generator of the patch: a source followed by its filters, in order.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GeneratorSpec {
+    #[serde(flatten)]
+    pub source: Source,
+    #[serde(default)]
+    pub filters: Vec<FilterSpec>,
+}
+
+
+/// Routing of the patch's generators to the output.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConsumerSpec {
+    /// The same stream on every channel.
+    Mono { input: String },
+    /// Two streams, optionally balanced (see `control::mux::balance`).
+    Stereo {
+        left: String,
+        right: String,
+        balance: Option<Param>,
+    },
+    /// Any number of streams mixed onto `channels` outputs by a routing matrix with one row of
+    /// input gains per output, defaulting to input `i` on output `i`.
+    Multi {
+        channels: usize,
+        inputs: Vec<String>,
+        routing: Option<Vec<Vec<f32>>>,
+    },
+}
+
+
+/// A patch as described by a patch file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Patch {
+    #[serde(default)]
+    pub generators: HashMap<String, GeneratorSpec>,
+    pub consumer: ConsumerSpec,
+    /// Directory relative sample paths are resolved against: that of the patch file when loaded
+    /// with `Patch::load`, otherwise the current directory.
+    #[serde(skip)]
+    pub directory: Option<PathBuf>,
+}
+
+
+impl Patch {
+    pub fn parse(source: &str) -> Result<Self> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read patch '{}'", path.display()))?;
+        let mut patch = Self::parse(&source)
+            .with_context(|| format!("invalid patch '{}'", path.display()))?;
+        patch.directory = path.parent().map(Path::to_path_buf);
+        Ok(patch)
+    }
+
+    /// Number of output channels the patch requires, if it requires any in particular.
+    pub fn channels(&self) -> Option<usize> {
+        match &self.consumer {
+            ConsumerSpec::Multi { channels, .. } => Some(*channels),
+            _ => None,
+        }
+    }
+
+    /// Build the `Consumer` described by the patch, for a device with `channels` channels.
+    ///
+    /// Fails on references to missing generators, on generators referencing themselves and on
+    /// `multi` consumers that don't fit the device's `channels`.
+    pub fn build(&self, sample_rate: u32, channels: usize) -> Result<Box<dyn Consumer>> {
+        let builder = Builder { patch: self, sample_rate };
+        match &self.consumer {
+            ConsumerSpec::Mono { input } => {
+                let generator = builder.generator(input, &mut Vec::new())?;
+                Ok(Box::new(MonoConsumer::new(channels).bind(generator)))
+            },
+            ConsumerSpec::Stereo { left, right, balance } => {
+                let left = builder.generator(left, &mut Vec::new())?;
+                let right = builder.generator(right, &mut Vec::new())?;
+                let (left, right) = match balance {
+                    Some(balance) => mux::balance(builder.pot(balance)?, left, right),
+                    None => (left, right),
+                };
+                Ok(Box::new(StereoConsumer::new(channels).bind(left, right)))
+            },
+            ConsumerSpec::Multi { channels: outputs, inputs, routing } => {
+                if *outputs != channels {
+                    return Err(anyhow!(
+                        "patch routes to {} channels but the device has {}", outputs, channels
+                    ));
+                }
+                let generators = inputs
+                    .iter()
+                    .map(|input| builder.generator(input, &mut Vec::new()))
+                    .collect::<Result<Vec<Generator>>>()?;
+                let routing = match routing {
+                    Some(matrix) => Routing::matrix(matrix.clone()),
+                    None => Routing::identity(channels),
+                };
+                Ok(Box::new(MultiChannelConsumer::with_routing(channels, generators, routing)?))
+            },
+        }
+    }
+}
+
+
+/// `Pot` built from a `Param`.
+pub enum ParamPot {
+    Value(f32),
+    Generator { pot: GeneratorPot, low: f32, high: f32 },
+}
+
+
+impl Pot<f32> for ParamPot {
+    fn read(&self) -> f32 {
+        match self {
+            ParamPot::Value(value) => *value,
+            ParamPot::Generator { pot, low, high } => {
+                let value: f32 = pot.read();
+                low + (value + 1.0) / 2.0 * (high - low)
+            },
+        }
+    }
+}
+
+
+impl Pot<f64> for ParamPot {
+    fn read(&self) -> f64 {
+        let value: f32 = self.read();
+        value as f64
+    }
+}
+
+
+// builds the streams of a patch, tracking the generators being built to catch cycles
+struct Builder<'a> {
+    patch: &'a Patch,
+    sample_rate: u32,
+}
+
+
+impl<'a> Builder<'a> {
+    fn generator(&self, name: &str, building: &mut Vec<String>) -> Result<Generator> {
+        let spec = self.patch.generators
+            .get(name)
+            .ok_or_else(|| anyhow!("no generator named '{}'", name))?;
+        if building.iter().any(|n| n == name) {
+            return Err(anyhow!("generator '{}' depends on itself", name));
+        }
+        building.push(name.to_string());
+
+        let rate = self.sample_rate;
+        let mut generator = match &spec.source {
+            Source::Sine { frequency } => generator::sine(rate, self.param(frequency, building)?),
+            Source::Square { frequency } => {
+                generator::square(rate, self.param(frequency, building)?)
+            },
+            Source::Sawtooth { frequency } => {
+                generator::sawtooth(rate, self.param(frequency, building)?)
+            },
+            Source::White => generator::white(),
+            Source::Silence => generator::silence(),
+            Source::Sample { path } => {
+                let path = match &self.patch.directory {
+                    Some(directory) => directory.join(path),
+                    None => PathBuf::from(path),
+                };
+                generator::repeat(VecTrack::try_from_wav_file(rate, path)?)
+            },
+            Source::Mix { inputs, gains } => {
+                let inputs = inputs
+                    .iter()
+                    .map(|input| self.generator(input, building))
+                    .collect::<Result<Vec<Generator>>>()?;
+                let gains: Vec<f32> = (0 .. inputs.len())
+                    .map(|i| gains.get(i).copied().unwrap_or(1.0))
+                    .collect();
+                let mix_function = move |inputs: &[Sample], outputs: &mut [Sample]| {
+                    outputs[0] = inputs.iter().zip(gains.iter()).map(|(x, g)| x * g).sum();
+                };
+                mux::muxn(mix_function, inputs, 1).remove(0)
+            },
+        };
+        for spec in spec.filters.iter() {
+            let filter = self.filter(spec, building)?;
+            generator = filter::compose(generator, filter);
+        }
+
+        building.pop();
+        Ok(generator)
+    }
+
+    fn filter(&self, spec: &FilterSpec, building: &mut Vec<String>) -> Result<Filter> {
+        let rate = self.sample_rate;
+        let filter = match spec {
+            FilterSpec::Gain { factor } => filter::gain(self.param(factor, building)?),
+            FilterSpec::Offset { offset } => filter::offset(self.param(offset, building)?),
+            FilterSpec::Clip { low, high } => {
+                filter::clip(self.param(low, building)?, self.param(high, building)?)
+            },
+            FilterSpec::Warble { period } => filter::warble(rate, *period),
+            FilterSpec::RampUp { secs } => filter::ramp_up(rate, *secs),
+            FilterSpec::Comb { delay_secs, decay, feedback } => {
+                let direction = if *feedback {
+                    filter::CombDirection::FeedBack
+                } else {
+                    filter::CombDirection::FeedForward
+                };
+                filter::comb(rate, *delay_secs, *decay, direction)
+            },
+            FilterSpec::AllPass { delay_secs, decay } => {
+                filter::all_pass(rate, *delay_secs, *decay)
+            },
+            FilterSpec::Reverb => filter::reverb(rate, 0.0, 0.0),
+            FilterSpec::DcBlock { cutoff } => filter::dc_block(rate, *cutoff),
+            FilterSpec::LowPass { cutoff, resonance } => {
+                self.svf(cutoff, resonance, SvfMode::LowPass, building)?
+            },
+            FilterSpec::HighPass { cutoff, resonance } => {
+                self.svf(cutoff, resonance, SvfMode::HighPass, building)?
+            },
+            FilterSpec::BandPass { cutoff, resonance } => {
+                self.svf(cutoff, resonance, SvfMode::BandPass, building)?
+            },
+            FilterSpec::Notch { cutoff, resonance } => {
+                self.svf(cutoff, resonance, SvfMode::Notch, building)?
+            },
+            FilterSpec::Ladder { cutoff, resonance } => filter::ladder(
+                rate,
+                self.param(cutoff, building)?,
+                self.param(resonance, building)?,
+            ),
+        };
+        Ok(filter)
+    }
+
+    fn svf(
+        &self,
+        cutoff: &Param,
+        resonance: &Param,
+        mode: SvfMode,
+        building: &mut Vec<String>,
+    ) -> Result<Filter> {
+        let cutoff = self.param(cutoff, building)?;
+        let resonance = self.param(resonance, building)?;
+        Ok(filter::svf(self.sample_rate, cutoff, resonance, mode))
+    }
+
+    fn param(&self, param: &Param, building: &mut Vec<String>) -> Result<ParamPot> {
+        match param {
+            Param::Value(value) => Ok(ParamPot::Value(*value)),
+            Param::Generator { generator, low, high } => Ok(ParamPot::Generator {
+                pot: GeneratorPot::new(self.generator(generator, building)?),
+                low: *low,
+                high: *high,
+            }),
+        }
+    }
+
+    // parameters outside of any generator, e.g. the consumer's balance
+    fn pot(&self, param: &Param) -> Result<ParamPot> {
+        self.param(param, &mut Vec::new())
+    }
+}
+
+
+/// Control side of a swappable patch, see `swappable`.
+pub struct Swapper {
+    patches: Producer<Box<dyn Consumer>>,
+    garbage: ringbuf::Consumer<Box<dyn Consumer>>,
+}
+
+
+impl Swapper {
+    /// Crossfade the stream over to the provided patch.
+    ///
+    /// Fails if too many swaps are already waiting for the audio thread.
+    pub fn swap(&mut self, patch: Box<dyn Consumer>) -> Result<()> {
+        self.collect_garbage();
+        self.patches
+            .push(patch)
+            .map_err(|_| anyhow!("too many patch swaps pending"))
+    }
+
+    /// Drop the patches swapped out by the audio thread.
+    pub fn collect_garbage(&mut self) {
+        while self.garbage.pop().is_some() {}
+    }
+}
+
+
+/// Audio side of a swappable patch, see `swappable`.
+pub struct Swappable {
+    current: Box<dyn Consumer>,
+    next: Option<(Box<dyn Consumer>, usize)>,
+    patches: ringbuf::Consumer<Box<dyn Consumer>>,
+    garbage: Producer<Box<dyn Consumer>>,
+    scratch: Vec<Sample>,
+    channels: usize,
+    fade_frames: usize,
+}
+
+
+impl Consumer for Swappable {
+    fn fill(&mut self, output_buffer: &mut [Sample]) {
+        self.current.fill(output_buffer);
+        if self.next.is_none() {
+            self.next = self.patches.pop().map(|patch| (patch, 0));
+        }
+        if let Some((mut next, mut position)) = self.next.take() {
+            // only allocates when the device hands over a larger buffer than ever before
+            self.scratch.resize(output_buffer.len(), 0.0);
+            next.fill(&mut self.scratch);
+            let frames = output_buffer.chunks_mut(self.channels);
+            for (frame, next_frame) in frames.zip(self.scratch.chunks(self.channels)) {
+                position += 1;
+                let t = (position as Sample / self.fade_frames as Sample).min(1.0);
+                for (location, &incoming) in frame.iter_mut().zip(next_frame.iter()) {
+                    *location += (incoming - *location) * t;
+                }
+            }
+            if position >= self.fade_frames {
+                let old = std::mem::replace(&mut self.current, next);
+                // only dropped here if the control side has stopped collecting garbage
+                let _ = self.garbage.push(old);
+            } else {
+                self.next = Some((next, position));
+            }
+        }
+    }
+}
+
+
+/// Play the provided patch on a device with `channels` channels until a `Swapper::swap` replaces
+/// it, crossfading between the two over `fade_secs` seconds.
+///
+/// Patches are handed to and from the audio thread through lock-free queues, and replaced
+/// patches are dropped on the control side by `Swapper::collect_garbage`.
+pub fn swappable(
+    sample_rate: u32,
+    fade_secs: f32,
+    channels: usize,
+    initial: Box<dyn Consumer>,
+) -> (Swapper, Swappable) {
+    const QUEUE_CAPACITY: usize = 4;
+    let (patch_producer, patch_consumer) = RingBuffer::new(QUEUE_CAPACITY).split();
+    let (garbage_producer, garbage_consumer) = RingBuffer::new(QUEUE_CAPACITY).split();
+    let swappable = Swappable {
+        current: initial,
+        next: None,
+        patches: patch_consumer,
+        garbage: garbage_producer,
+        scratch: Vec::new(),
+        channels: channels.max(1),
+        fade_frames: ((fade_secs * sample_rate as f32).round() as usize).max(1),
+    };
+    (Swapper { patches: patch_producer, garbage: garbage_consumer }, swappable)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PATCH: &str = r#"
+        [generators.one]
+        type = "silence"
+        filters = [{ type = "offset", offset = 1 }]
+
+        [generators.mix]
+        type = "mix"
+        inputs = ["one", "one"]
+        gains = [0.25]
+        filters = [{ type = "gain", factor = { generator = "one", low = 0, high = 2 } }]
+
+        [consumer]
+        type = "stereo"
+        left = "one"
+        right = "mix"
+    "#;
+
+    fn constant(value: Sample) -> Box<dyn Consumer> {
+        Box::new(MonoConsumer::new(2).bind(Box::new(move || value)))
+    }
+
+    #[test]
+    fn test_build_patch() {
+        let patch = Patch::parse(PATCH).unwrap();
+        let mut buffer = [0.0; 2];
+        patch.build(44100, 2).unwrap().fill(&mut buffer);
+        assert_eq!(buffer, [1.0, 2.5]);
+
+        let mut broken = patch.clone();
+        broken.generators.get_mut("one").unwrap().source = Source::Sine {
+            frequency: Param::Generator { generator: "mix".into(), low: -1.0, high: 1.0 },
+        };
+        assert!(broken.build(44100, 2).is_err());
+        assert!(Patch::parse("[consumer]\ntype = \"mono\"\ninput = \"missing\"")
+            .unwrap()
+            .build(44100, 2)
+            .is_err());
+        let example = Patch::parse(include_str!("../patches/balance.toml")).unwrap();
+        assert!(example.build(44100, 2).is_ok());
+    }
+
+    #[test]
+    fn test_modulated_sawtooth() {
+        let mut patch = Patch::parse(PATCH).unwrap();
+        patch.generators.get_mut("mix").unwrap().source = Source::Sawtooth {
+            frequency: Param::Generator { generator: "one".into(), low: 0.0, high: 1.0 },
+        };
+        patch.generators.get_mut("mix").unwrap().filters.clear();
+        let mut buffer = [0.0; 4];
+        patch.build(4, 2).unwrap().fill(&mut buffer);
+        assert_eq!(buffer, [1.0, 0.25, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_sample_path_relative_to_patch() {
+        let directory = std::env::temp_dir().join("psynth-test-patch");
+        std::fs::create_dir_all(&directory).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 4,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(directory.join("kick.wav"), spec).unwrap();
+        writer.write_sample(0.5f32).unwrap();
+        writer.finalize().unwrap();
+        let patch_path = directory.join("kick.toml");
+        std::fs::write(
+            &patch_path,
+            "[generators.kick]\ntype = \"sample\"\npath = \"kick.wav\"\n\
+            [consumer]\ntype = \"mono\"\ninput = \"kick\"",
+        ).unwrap();
+
+        let patch = Patch::load(&patch_path).unwrap();
+        let mut buffer = [0.0; 2];
+        let built = patch.build(4, 2).map(|mut consumer| consumer.fill(&mut buffer));
+        std::fs::remove_dir_all(&directory).unwrap();
+        built.unwrap();
+        assert_eq!(buffer, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_build_multi() {
+        let mut patch = Patch::parse(PATCH).unwrap();
+        patch.consumer = Patch::parse(
+            "[consumer]\ntype = \"multi\"\nchannels = 3\ninputs = [\"one\", \"mix\"]\n\
+            routing = [[1, 0], [0, 1], [0.5, 0.5]]",
+        ).unwrap().consumer;
+        assert_eq!(patch.channels(), Some(3));
+        assert!(patch.build(44100, 2).is_err());
+
+        let mut buffer = [0.0; 3];
+        patch.build(44100, 3).unwrap().fill(&mut buffer);
+        assert_eq!(buffer, [1.0, 2.5, 1.75]);
+    }
+
+    #[test]
+    fn test_swap_crossfades() {
+        let (mut swapper, mut consumer) = swappable(4, 1.0, 2, constant(0.0));
+        let mut buffer = [1.0; 2];
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer, [0.0; 2]);
+
+        swapper.swap(constant(1.0)).unwrap();
+        let mut buffer = [0.0; 10];
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer, [0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 1.0, 1.0, 1.0, 1.0]);
+        let mut buffer = [0.0; 2];
+        consumer.fill(&mut buffer);
+        assert_eq!(buffer, [1.0; 2]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Offline rendering, without an output device.
+//!
+//! A `Consumer` doesn't care who hands it buffers, so rendering is just calling `fill` in a loop
+//! as fast as the patch can be computed rather than at the pace of a sound card. This bounces
+//! patches to WAV files, produces test fixtures and lets patches be worked on without any audio
+//! hardware.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use hound::{WavSpec, SampleFormat};
+
+use crate::{Sample, Generator, Consumer};
+use crate::consumer::MonoConsumer;
+
+
+/// Number of frames handed to the `Consumer` per `fill` call while rendering.
+pub const RENDER_BLOCK_FRAMES: usize = 1024;
+
+
+/// Number of frames in `secs` seconds of audio at the provided sample rate.
+pub fn frames(sample_rate: u32, secs: f64) -> usize {
+    (secs.max(0.0) * sample_rate as f64).round() as usize
+}
+
+
+/// Pull `n_frames` frames of `channels`-channel audio from the provided `Consumer`, interleaved
+/// as they would have been written to a device.
+pub fn render<C>(consumer: &mut C, channels: usize, n_frames: usize) -> Vec<Sample>
+where
+    C: Consumer + ?Sized,
+{
+    let mut samples = vec![0.0; n_frames * channels];
+    for block in samples.chunks_mut(RENDER_BLOCK_FRAMES * channels.max(1)) {
+        consumer.fill(block);
+    }
+    samples
+}
+
+
+/// Pull `n_frames` samples from the provided `Generator`.
+pub fn render_generator(generator: Generator, n_frames: usize) -> Vec<Sample> {
+    render(&mut MonoConsumer::new(1).bind(generator), 1, n_frames)
+}
+
+
+/// Spec of the WAV files written by `render_to_wav`: 32-bit float at the provided sample rate.
+pub fn wav_spec(sample_rate: u32, channels: u16) -> WavSpec {
+    WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    }
+}
+
+
+/// Render `secs` seconds of `channels`-channel audio from the provided `Consumer` to a WAV file
+/// at `path`.
+///
+/// Audio is streamed to the file a block at a time, so renders of any length run in constant
+/// memory.
+pub fn render_to_wav<C, P>(
+    path: P,
+    consumer: &mut C,
+    sample_rate: u32,
+    channels: u16,
+    secs: f64,
+) -> Result<()>
+where
+    C: Consumer + ?Sized,
+    P: AsRef<Path>,
+{
+    if channels == 0 {
+        return Err(anyhow!("unable to render audio with 0 channels"));
+    }
+    let mut writer = hound::WavWriter::create(path, wav_spec(sample_rate, channels))?;
+    let mut buffer = vec![0.0; RENDER_BLOCK_FRAMES * channels as usize];
+    let mut remaining = frames(sample_rate, secs);
+    while remaining > 0 {
+        let n_frames = remaining.min(RENDER_BLOCK_FRAMES);
+        let block = &mut buffer[.. n_frames * channels as usize];
+        consumer.fill(block);
+        for &sample in block.iter() {
+            writer.write_sample(sample)?;
+        }
+        remaining -= n_frames;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+
+/// Render `secs` seconds of the provided `Generator` to a mono WAV file at `path`.
+pub fn generator_to_wav<P>(path: P, generator: Generator, sample_rate: u32, secs: f64) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    render_to_wav(path, &mut MonoConsumer::new(1).bind(generator), sample_rate, 1, secs)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consumer::StereoConsumer;
+
+    #[test]
+    fn test_render_to_wav() {
+        let mut counter = 0;
+        let samples = render_generator(Box::new(move || {
+            counter += 1;
+            counter as Sample
+        }), 2500);
+        assert_eq!(samples.len(), 2500);
+        assert_eq!(samples[2499], 2500.0);
+
+        let path = std::env::temp_dir().join("psynth-test-render.wav");
+        let mut consumer = StereoConsumer::new(2).bind(Box::new(|| 0.25), Box::new(|| -0.5));
+        render_to_wav(&path, &mut consumer, 1000, 2, 1.5).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec(), wav_spec(1000, 2));
+        let written: Vec<f32> = reader.samples().map(|s| s.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), 3000);
+        assert!(written.chunks(2).all(|frame| frame == [0.25, -0.5]));
+    }
+}
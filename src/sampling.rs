@@ -1,11 +1,13 @@
 //! Predetermined waveforms for repeated playback.
 
+use std::f32::consts::PI;
 use std::path::Path;
 
 use anyhow::{anyhow, Result};
 use hound::{WavReader, WavSpec, SampleFormat};
 
-use crate::{Sample, Generator};
+use crate::{filter, Sample, Generator};
+use crate::music::notes::Tone;
 
 
 pub trait SampleTrack {
@@ -72,6 +74,114 @@ impl VecTrack {
             counter: 0,
         })
     }
+
+    /// Stretch the track by `factor` in time without altering its pitch, e.g. a `factor` of 2
+    /// plays for twice as long.
+    ///
+    /// Uses waveform-similarity overlap-add (WSOLA): Hann-windowed frames are laid down at a fixed
+    /// output hop and read from the input at the stretched hop, with each frame's read position
+    /// nudged to where it best lines up with the natural continuation of the previous frame, which
+    /// avoids the phasing of plain overlap-add.
+    pub fn time_stretch(&self, factor: f32) -> Self {
+        let factor = factor.max(f32::EPSILON);
+        let input_len = self.track.len();
+        let output_len = (input_len as f32 * factor).round() as usize;
+        if input_len < WSOLA_FRAME + 2 * WSOLA_TOLERANCE {
+            // too short to find meaningful overlaps, fall back to resampling
+            return VecTrack { track: resample(&self.track, 1.0 / factor), counter: 0 };
+        }
+
+        let window: Vec<f32> = (0 .. WSOLA_FRAME)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / WSOLA_FRAME as f32).cos())
+            .collect();
+        let input_hop = WSOLA_HOP as f32 / factor;
+        let last_start = input_len - WSOLA_FRAME;
+        let mut output = vec![0f32; output_len + WSOLA_FRAME];
+        let mut previous: Option<usize> = None;
+
+        for (k, out_start) in (0 .. output_len).step_by(WSOLA_HOP).enumerate() {
+            let nominal = ((k as f32 * input_hop) as usize).min(last_start);
+            let start = match previous {
+                None => nominal,
+                Some(prev) => {
+                    let continuation = (prev + WSOLA_HOP).min(last_start);
+                    let low = nominal.saturating_sub(WSOLA_TOLERANCE);
+                    let high = (nominal + WSOLA_TOLERANCE).min(last_start);
+                    best_overlap(&self.track, continuation, low, high)
+                },
+            };
+            // the first and last frames have no neighbour to overlap with on one side
+            let is_first = k == 0;
+            let is_last = out_start + WSOLA_HOP >= output_len;
+            for (i, w) in window.iter().enumerate() {
+                let flat = (is_first && i < WSOLA_HOP) || (is_last && i >= WSOLA_HOP);
+                let weight = if flat { 1.0 } else { *w };
+                output[out_start + i] += weight * self.track[start + i];
+            }
+            previous = Some(start);
+        }
+
+        output.truncate(output_len);
+        VecTrack { track: output, counter: 0 }
+    }
+
+    /// Shift the pitch of the track by the provided number of `semitones` while keeping its
+    /// duration.
+    pub fn transpose(&self, semitones: f32) -> Self {
+        let ratio = filter::pitch::semitones_to_ratio(semitones);
+        // resampling raises the pitch by `ratio` and shortens the track by the same amount
+        let resampled = VecTrack { track: resample(&self.track, ratio), counter: 0 };
+        resampled.time_stretch(ratio)
+    }
+
+    /// Transpose a track recorded at the `recorded` `Tone` such that it plays at `target`.
+    pub fn retune(&self, recorded: &Tone, target: &Tone) -> Self {
+        self.transpose(target.semitone_distance_to(recorded) as f32)
+    }
+}
+
+
+/// Length of a WSOLA frame used by `VecTrack::time_stretch`, in samples.
+const WSOLA_FRAME: usize = 1024;
+
+/// Distance between the starts of successive output frames, 50% overlap for the Hann window.
+const WSOLA_HOP: usize = WSOLA_FRAME / 2;
+
+/// Farthest a frame's read position may be moved from its nominal position, in samples.
+const WSOLA_TOLERANCE: usize = WSOLA_FRAME / 4;
+
+
+// candidate start on [low, high] whose first half-frame best correlates with `reference`
+fn best_overlap(track: &[Sample], reference: usize, low: usize, high: usize) -> usize {
+    let overlap = &track[reference .. reference + WSOLA_HOP];
+    let mut best = (low, f32::MIN);
+    for candidate in low ..= high {
+        let correlation = track[candidate .. candidate + WSOLA_HOP]
+            .iter()
+            .zip(overlap.iter())
+            .step_by(2)
+            .fold(0f32, |acc, (a, b)| acc + a * b);
+        if correlation > best.1 {
+            best = (candidate, correlation);
+        }
+    }
+    best.0
+}
+
+
+// linearly interpolated resampling that plays `track` back `ratio` times faster
+fn resample(track: &[Sample], ratio: f32) -> Vec<Sample> {
+    let output_len = (track.len() as f32 / ratio).floor() as usize;
+    (0 .. output_len)
+        .map(|i| {
+            let position = i as f32 * ratio;
+            let index = position as usize;
+            let frac = position - index as f32;
+            let a = track.get(index).copied().unwrap_or(0.0);
+            let b = track.get(index + 1).copied().unwrap_or(a);
+            a + frac * (b - a)
+        })
+        .collect()
 }
 
 impl SampleTrack for VecTrack {
@@ -99,3 +209,22 @@ impl SampleTrack for Generator {
         // no resetting a Generator
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator;
+
+    #[test]
+    fn test_time_stretch() {
+        let track = VecTrack::from_generator(generator::sine(44100, 440.0), 44100);
+        for &factor in &[0.5, 1.0, 1.5] {
+            let stretched = track.time_stretch(factor);
+            assert_eq!(stretched.track.len(), (44100.0 * factor) as usize);
+            // overlapping windows should sum to unity and preserve the amplitude of a pure tone
+            let peak = stretched.track.iter().fold(0f32, |acc, s| acc.max(s.abs()));
+            assert!((peak - 1.0).abs() < 0.05);
+        }
+    }
+}